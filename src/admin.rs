@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use russh::server::Handle;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+use crate::channel::Channels;
+use crate::listener::{Listener, Sever};
+
+/// How long a kicked client has to confirm closing its channels before it is dropped.
+const KICK_GRACE: Duration = Duration::from_secs(5);
+
+/// An open connection, as listed on the admin socket.
+struct ConnectionInfo {
+    peer: Option<SocketAddr>,
+    user: Option<String>,
+    opened: Instant,
+    /// Handle of the session, once the client sent its identification.
+    handle: Option<Handle>,
+    /// Drops the connection, taken when it is kicked.
+    sever: Option<Sever>,
+    /// Why the connection was kicked, if it was.
+    kicked: Option<String>,
+}
+
+/// The open connections, by id.
+#[derive(Clone, Default)]
+pub struct Registry(Arc<Mutex<HashMap<usize, ConnectionInfo>>>);
+
+impl Registry {
+    /// Add connection `id` from `peer`, which can be dropped with `sever`.
+    pub async fn register(&self, id: usize, peer: Option<SocketAddr>, sever: Sever) {
+        self.0.lock().await.insert(
+            id,
+            ConnectionInfo {
+                peer,
+                user: None,
+                opened: Instant::now(),
+                handle: None,
+                sever: Some(sever),
+                kicked: None,
+            },
+        );
+    }
+
+    /// Record the handle of connection `id` once its session started.
+    pub async fn started(&self, id: usize, handle: Handle) {
+        if let Some(connection) = self.0.lock().await.get_mut(&id) {
+            connection.handle = Some(handle);
+        }
+    }
+
+    /// Record that connection `id` authenticated as `user`.
+    pub async fn authenticated(&self, id: usize, user: &str) {
+        if let Some(connection) = self.0.lock().await.get_mut(&id) {
+            connection.user = Some(user.to_string());
+        }
+    }
+
+    /// Forget connection `id` after it ended.
+    pub async fn remove(&self, id: usize) {
+        self.0.lock().await.remove(&id);
+    }
+
+    /// Drop connection `id` without telling the client.
+    pub async fn sever(&self, id: usize) {
+        let sever = match self.0.lock().await.get_mut(&id) {
+            Some(connection) => connection.sever.take(),
+            None => None,
+        };
+        if let Some(sever) = sever {
+            sever.sever();
+        }
+    }
+
+    /// Why connection `id` was kicked, if it was.
+    pub async fn kicked(&self, id: usize) -> Option<String> {
+        self.0.lock().await.get(&id)?.kicked.clone()
+    }
+
+    /// Disconnect connection `id`, telling the client `reason`, and return whether it exists.
+    ///
+    /// The channels of the connection, found in `channels`, are closed, and the client is
+    /// disconnected once it confirms closing one of them. Connections without channels, or
+    /// whose client does not confirm within [`KICK_GRACE`], are dropped without a message.
+    pub async fn kick(&self, id: usize, reason: &str, channels: &Channels) -> bool {
+        let (handle, sever) = {
+            let mut connections = self.0.lock().await;
+            let Some(connection) = connections.get_mut(&id) else {
+                return false;
+            };
+            connection.kicked = Some(reason.to_string());
+            (connection.handle.clone(), connection.sever.take())
+        };
+        let Some(sever) = sever else {
+            // Kicked before, and going to be dropped anyway.
+            return true;
+        };
+        let open: Vec<_> = channels
+            .lock()
+            .await
+            .keys()
+            .filter(|(connection, _)| *connection == id)
+            .map(|(_, channel)| *channel)
+            .collect();
+        match handle {
+            Some(handle) if !open.is_empty() => {
+                for channel in open {
+                    let _ = handle.eof(channel).await;
+                    let _ = handle.close(channel).await;
+                }
+                tokio::spawn(async move {
+                    tokio::time::sleep(KICK_GRACE).await;
+                    sever.sever();
+                });
+            }
+            _ => sever.sever(),
+        }
+        true
+    }
+}
+
+/// Answer commands on the admin socket `listener` until the server stops.
+///
+/// The protocol is line based: each command is answered with zero or more lines of output,
+/// followed by `ok` or by `error: ` and the reason.
+///
+/// - `list` prints a line `<id> <peer> <user> <seconds> <channels>` for each connection, with
+///   `-` for an unknown peer or a connection that has not authenticated yet.
+/// - `kick <id> [reason]` disconnects connection `id`, telling the client why, or
+///   `default_reason` if no reason is given.
+/// - `stats` prints the numbers of `connections` and open `channels`, one per line.
+pub async fn serve(
+    listener: Listener,
+    registry: Registry,
+    channels: Channels,
+    default_reason: String,
+) {
+    let default_reason = Arc::new(default_reason);
+    loop {
+        let stream = match listener.accept().await {
+            Ok(connection) => connection.into_stream(),
+            Err(e) => {
+                tracing::warn!("Failed to accept admin connection: {}", e);
+                tokio::time::sleep(crate::listener::ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        let channels = channels.clone();
+        let default_reason = default_reason.clone();
+        let session = async move {
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = respond(line.trim(), &registry, &channels, &default_reason).await;
+                if writer.write_all(response.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::spawn(session.in_current_span());
+    }
+}
+
+async fn respond(
+    command: &str,
+    registry: &Registry,
+    channels: &Channels,
+    default_reason: &str,
+) -> String {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    match (name, argument.trim()) {
+        ("list", "") => list(registry, channels).await,
+        ("kick", argument) => {
+            let (id, reason) = argument.split_once(' ').unwrap_or((argument, ""));
+            let reason = match reason.trim() {
+                "" => default_reason,
+                reason => reason,
+            };
+            match id.parse() {
+                Ok(id) => kick(registry, channels, id, reason).await,
+                Err(_) => format!("error: invalid connection id {:?}\n", id),
+            }
+        }
+        ("stats", "") => {
+            let connections = registry.0.lock().await.len();
+            let channels = channels.lock().await.len();
+            format!("connections {}\nchannels {}\nok\n", connections, channels)
+        }
+        _ => format!("error: unknown command {:?}\n", command),
+    }
+}
+
+async fn list(registry: &Registry, channels: &Channels) -> String {
+    let mut open: HashMap<usize, usize> = HashMap::new();
+    for (id, _) in channels.lock().await.keys() {
+        *open.entry(*id).or_default() += 1;
+    }
+    let connections = registry.0.lock().await;
+    let mut ids: Vec<&usize> = connections.keys().collect();
+    ids.sort();
+    let mut response = String::new();
+    for id in ids {
+        let connection = &connections[id];
+        response.push_str(&format!(
+            "{} {} {} {} {}\n",
+            id,
+            connection
+                .peer
+                .map_or_else(|| "-".to_string(), |peer| peer.to_string()),
+            connection.user.as_deref().unwrap_or("-"),
+            connection.opened.elapsed().as_secs(),
+            open.get(id).copied().unwrap_or_default()
+        ));
+    }
+    response.push_str("ok\n");
+    response
+}
+
+async fn kick(registry: &Registry, channels: &Channels, id: usize, reason: &str) -> String {
+    if registry.kick(id, reason, channels).await {
+        tracing::info!(
+            "Kicking connection {} as requested on the admin socket: {}",
+            id,
+            reason
+        );
+        "ok\n".to_string()
+    } else {
+        format!("error: no connection {}\n", id)
+    }
+}
@@ -0,0 +1,111 @@
+use russh::{cipher, kex, mac, Preferred};
+
+/// Key exchange methods russh implements, in its order of preference.
+const KEX: &[kex::Name] = &[
+    kex::CURVE25519,
+    kex::DH_G14_SHA256,
+    kex::DH_G14_SHA1,
+    kex::DH_G1_SHA1,
+];
+
+/// Pseudo methods announcing support for extension negotiation, offered with any selection.
+const KEX_EXTENSIONS: &[kex::Name] = &[
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+];
+
+const CIPHERS: &[cipher::Name] = &[
+    cipher::CHACHA20_POLY1305,
+    cipher::AES_256_GCM,
+    cipher::AES_256_CTR,
+    cipher::AES_192_CTR,
+    cipher::AES_128_CTR,
+];
+
+/// MACs, without `none`, which ciphers with integrated authentication use anyway.
+const MACS: &[mac::Name] = &[
+    mac::HMAC_SHA512_ETM,
+    mac::HMAC_SHA256_ETM,
+    mac::HMAC_SHA512,
+    mac::HMAC_SHA256,
+    mac::HMAC_SHA1_ETM,
+    mac::HMAC_SHA1,
+];
+
+const COMPRESSION: &[&str] = &["none", "zlib", "zlib@openssh.com"];
+
+/// Algorithms to negotiate, each in the order of preference. Empty lists keep the defaults
+/// of russh.
+pub struct Selection<'a> {
+    pub kex: &'a [String],
+    pub ciphers: &'a [String],
+    pub macs: &'a [String],
+    pub compression: &'a [String],
+}
+
+impl Selection<'_> {
+    /// Names in the selection that russh does not implement, with the valid ones.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        check("kex", self.kex, KEX, &mut problems);
+        check("ciphers", self.ciphers, CIPHERS, &mut problems);
+        check("macs", self.macs, MACS, &mut problems);
+        check("compression", self.compression, COMPRESSION, &mut problems);
+        problems
+    }
+
+    /// The selection for the russh configuration, which keeps it for the lifetime of the server.
+    pub fn preferred(&self) -> anyhow::Result<Preferred> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            anyhow::bail!("{}", problems.join(", "));
+        }
+        let default = Preferred::default();
+        let kex = match select(self.kex, KEX) {
+            Some(mut kex) => {
+                kex.extend_from_slice(KEX_EXTENSIONS);
+                leak(kex)
+            }
+            None => default.kex,
+        };
+        Ok(Preferred {
+            kex,
+            key: default.key,
+            cipher: select(self.ciphers, CIPHERS).map_or(default.cipher, leak),
+            mac: select(self.macs, MACS).map_or(default.mac, leak),
+            compression: select(self.compression, COMPRESSION).map_or(default.compression, leak),
+        })
+    }
+}
+
+/// Report the names of `setting` missing from `known`.
+fn check<T: AsRef<str>>(setting: &str, names: &[String], known: &[T], problems: &mut Vec<String>) {
+    for name in names {
+        if !known.iter().any(|algorithm| algorithm.as_ref() == name) {
+            let valid: Vec<&str> = known.iter().map(AsRef::as_ref).collect();
+            problems.push(format!(
+                "{} contains unknown algorithm {:?}, valid ones are {}",
+                setting,
+                name,
+                valid.join(", ")
+            ));
+        }
+    }
+}
+
+/// The algorithms of `known` named in `names`, in that order, or `None` if `names` is empty.
+fn select<T: AsRef<str> + Copy>(names: &[String], known: &[T]) -> Option<Vec<T>> {
+    if names.is_empty() {
+        return None;
+    }
+    let selected = names
+        .iter()
+        .filter_map(|name| known.iter().find(|algorithm| algorithm.as_ref() == name))
+        .copied()
+        .collect();
+    Some(selected)
+}
+
+fn leak<T>(algorithms: Vec<T>) -> &'static [T] {
+    Box::leak(algorithms.into_boxed_slice())
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+/// Outcome of an authentication attempt, as recorded in the audit log.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Accept,
+    Reject,
+}
+
+impl Outcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Accept => "accept",
+            Outcome::Reject => "reject",
+        }
+    }
+}
+
+/// A single authentication attempt.
+#[derive(Debug, Serialize)]
+pub struct AuthEvent<'a> {
+    pub peer: Option<IpAddr>,
+    pub user: &'a str,
+    pub method: &'a str,
+    /// SHA256 fingerprint of the offered key, for public key authentication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// Append-only log of authentication attempts, one JSON object per line.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+    /// Whether to write events to the normal log because the file could not be opened.
+    fallback: bool,
+}
+
+impl AuditLog {
+    /// Append to the audit log at `path`, creating it if needed.
+    ///
+    /// If the file cannot be opened, events go to the normal log instead.
+    pub fn open(path: &Path) -> Self {
+        match File::options().create(true).append(true).open(path) {
+            Ok(file) => AuditLog {
+                file: Some(Arc::new(Mutex::new(file))),
+                fallback: false,
+            },
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to open audit log {}, writing to the normal log instead: {}",
+                    path.display(),
+                    e
+                );
+                AuditLog {
+                    file: None,
+                    fallback: true,
+                }
+            }
+        }
+    }
+
+    /// Record an authentication attempt.
+    pub fn record(&self, event: &AuthEvent<'_>) {
+        if self.file.is_none() && !self.fallback {
+            return;
+        }
+        let line = match serde_json::to_string(&Line {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+        }) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!("Failed to serialize audit event: {}", e);
+                return;
+            }
+        };
+        match &self.file {
+            Some(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = writeln!(file, "{}", line).and_then(|()| file.flush()) {
+                    tracing::warn!("Failed to write audit log: {}", e);
+                }
+            }
+            None => tracing::info!(target: "audit", "{}", line),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a AuthEvent<'a>,
+}
@@ -0,0 +1,97 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+/// How long the program may take to decide before the attempt is rejected.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An authentication attempt, as written to the program's stdin.
+#[derive(Debug, Serialize)]
+pub struct Attempt<'a> {
+    pub user: &'a str,
+    pub method: &'a str,
+    pub peer: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<&'a str>,
+    /// The offered public key in the `authorized_keys` format, like `ssh-ed25519 AAAA...`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Answers to the prompts of earlier `partial` results, oldest first.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub responses: &'a [String],
+}
+
+/// What the program decided about an attempt.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Verdict {
+    Accept,
+    Reject,
+    /// Ask the client `prompt` and run the program again with the answer, only possible with
+    /// `keyboard-interactive`.
+    Partial {
+        prompt: String,
+    },
+}
+
+/// Ask the program at `path` about `attempt`.
+///
+/// The program accepts by exiting with status 0, and may print a JSON object like
+/// `{"result": "partial", "prompt": "Verification code: "}` to decide otherwise. Any other
+/// status, invalid output, a failure to run or a timeout rejects the attempt.
+pub async fn decide(path: &Path, attempt: &Attempt<'_>) -> Verdict {
+    match tokio::time::timeout(TIMEOUT, run(path, attempt)).await {
+        Ok(Ok(verdict)) => verdict,
+        Ok(Err(e)) => {
+            tracing::warn!("Rejecting the attempt: {:#}", e);
+            Verdict::Reject
+        }
+        Err(_) => {
+            tracing::warn!(
+                "Rejecting the attempt: auth command {} did not finish within {}s",
+                path.display(),
+                TIMEOUT.as_secs()
+            );
+            Verdict::Reject
+        }
+    }
+}
+
+async fn run(path: &Path, attempt: &Attempt<'_>) -> anyhow::Result<Verdict> {
+    let mut child = tokio::process::Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run auth command {}", path.display()))?;
+    let mut input = serde_json::to_vec(attempt)?;
+    input.push(b'\n');
+    if let Some(mut stdin) = child.stdin.take() {
+        // Programs deciding without reading their input close stdin early.
+        let _ = stdin.write_all(&input).await;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .with_context(|| format!("failed to run auth command {}", path.display()))?;
+    if !output.status.success() {
+        tracing::debug!(
+            "Auth command {} rejected the attempt: {}",
+            path.display(),
+            output.status
+        );
+        return Ok(Verdict::Reject);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        return Ok(Verdict::Accept);
+    }
+    serde_json::from_str(&stdout)
+        .with_context(|| format!("invalid output of auth command {}", path.display()))
+}
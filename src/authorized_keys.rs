@@ -0,0 +1,334 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use ipnet::IpNet;
+
+use russh_keys::key::PublicKey;
+use serde::Deserialize;
+
+/// Length of a SHA256 digest, as encoded in fingerprints.
+const FINGERPRINT_LENGTH: usize = 32;
+
+/// A key listed in the `keys` of a user.
+///
+/// Either a public key line as found in `id_ed25519.pub`, or the SHA256 fingerprint of a key,
+/// with or without the `SHA256:` prefix.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AllowedKey {
+    Key(PublicKey),
+    /// The base64-encoded digest, without the prefix.
+    Fingerprint(String),
+}
+
+impl AllowedKey {
+    pub fn matches(&self, key: &PublicKey) -> bool {
+        match self {
+            AllowedKey::Key(allowed) => allowed == key,
+            AllowedKey::Fingerprint(fingerprint) => *fingerprint == key.fingerprint(),
+        }
+    }
+}
+
+impl TryFrom<String> for AllowedKey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        if value.split_whitespace().any(is_key_type) {
+            return match parse_line(value) {
+                Ok(Some(entry)) if entry.options == Options::default() => {
+                    Ok(AllowedKey::Key(entry.key))
+                }
+                Ok(Some(_)) => Err(format!(
+                    "options of {:?} are only supported in authorized_keys files",
+                    value
+                )),
+                _ => Err(format!("invalid public key {:?}", value)),
+            };
+        }
+        let fingerprint = value.strip_prefix("SHA256:").unwrap_or(value);
+        match data_encoding::BASE64_NOPAD.decode(fingerprint.as_bytes()) {
+            Ok(digest) if digest.len() == FINGERPRINT_LENGTH => {
+                Ok(AllowedKey::Fingerprint(fingerprint.to_string()))
+            }
+            _ => Err(format!(
+                "{:?} is neither a public key nor a SHA256 fingerprint",
+                value
+            )),
+        }
+    }
+}
+
+/// Options of an `authorized_keys` line, restricting what the key may be used for.
+///
+/// `no-agent-forwarding` and `no-user-rc` are accepted as well, and always in effect since
+/// neither agent forwarding nor `~/.ssh/rc` is supported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Command run instead of the one requested, like `forced_command`.
+    pub command: Option<String>,
+    /// Patterns of the addresses the key may be used from, either wildcards or networks like
+    /// `10.0.0.0/8`, each possibly negated with `!`.
+    pub from: Option<Vec<String>>,
+    pub no_pty: bool,
+    pub no_port_forwarding: bool,
+    pub no_x11_forwarding: bool,
+}
+
+impl Options {
+    /// Parse the comma-separated options at the start of a line.
+    fn parse(options: &str) -> Result<Self, String> {
+        let mut parsed = Options::default();
+        for option in split_unquoted(options, |c| c == ',') {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(unquote(value)?)),
+                None => (option, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("command", Some(command)) => parsed.command = Some(command),
+                ("from", Some(from)) => {
+                    parsed.from = Some(from.split(',').map(str::to_string).collect())
+                }
+                ("restrict", None) => {
+                    parsed.no_pty = true;
+                    parsed.no_port_forwarding = true;
+                    parsed.no_x11_forwarding = true;
+                }
+                ("no-pty", None) => parsed.no_pty = true,
+                ("pty", None) => parsed.no_pty = false,
+                ("no-port-forwarding", None) => parsed.no_port_forwarding = true,
+                ("port-forwarding", None) => parsed.no_port_forwarding = false,
+                ("no-x11-forwarding", None) => parsed.no_x11_forwarding = true,
+                ("x11-forwarding", None) => parsed.no_x11_forwarding = false,
+                ("no-agent-forwarding" | "agent-forwarding" | "no-user-rc" | "user-rc", None) => {}
+                _ => return Err(format!("unsupported option {:?}", option)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Check whether the key may be used by a client connecting from `peer`.
+    ///
+    /// Like OpenSSH, a negated pattern matching the address refuses it, and otherwise one of
+    /// the other patterns has to match.
+    pub fn allows(&self, peer: Option<IpAddr>) -> bool {
+        let Some(patterns) = &self.from else {
+            return true;
+        };
+        let Some(ip) = peer.map(|ip| ip.to_canonical()) else {
+            return false;
+        };
+        let matches = |pattern: &str| match pattern.parse::<IpNet>() {
+            Ok(net) => net.contains(&ip),
+            Err(_) => crate::glob_match(pattern, &ip.to_string()),
+        };
+        let mut allowed = false;
+        for pattern in patterns {
+            match pattern.strip_prefix('!') {
+                Some(negated) if matches(negated) => return false,
+                Some(_) => {}
+                None => allowed |= matches(pattern),
+            }
+        }
+        allowed
+    }
+}
+
+/// A key of an `authorized_keys` file with the options of its line.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: PublicKey,
+    pub options: Options,
+}
+
+/// Parse a line of an OpenSSH `authorized_keys` file, the key type and base64-encoded key
+/// optionally preceded by options and followed by a comment.
+///
+/// Lines without a parseable key yield `Ok(None)`, and lines with options that are not
+/// supported an error, so they are never accepted without their restrictions.
+pub fn parse_line(line: &str) -> Result<Option<Entry>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (options, rest) = match line.split_whitespace().next() {
+        Some(first) if is_key_type(first) => (Options::default(), line),
+        _ => match split_unquoted(line, char::is_whitespace).next() {
+            Some(options) => (Options::parse(options)?, line[options.len()..].trim_start()),
+            None => return Ok(None),
+        },
+    };
+    let mut tokens = rest.split_whitespace();
+    let key = match (tokens.next(), tokens.next()) {
+        (Some(kind), Some(key)) if is_key_type(kind) => {
+            russh_keys::parse_public_key_base64(key).ok()
+        }
+        _ => None,
+    };
+    Ok(key.map(|key| Entry { key, options }))
+}
+
+/// Split `text` at the characters matching `separator` outside of double quotes.
+fn split_unquoted(text: &str, separator: impl Fn(char) -> bool) -> impl Iterator<Item = &str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if !quoted && separator(c) => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts.into_iter().filter(|part| !part.is_empty())
+}
+
+/// The value of an option, without its double quotes and with `\"` unescaped.
+fn unquote(value: &str) -> Result<String, String> {
+    match value.strip_prefix('"') {
+        Some(quoted) => quoted
+            .strip_suffix('"')
+            .map(|inner| inner.replace("\\\"", "\""))
+            .ok_or_else(|| format!("unterminated quote in {:?}", value)),
+        None => Ok(value.to_string()),
+    }
+}
+
+fn is_key_type(token: &str) -> bool {
+    token.starts_with("ssh-")
+        || token.starts_with("ecdsa-")
+        || token.starts_with("sk-")
+        || token.starts_with("rsa-")
+}
+
+/// Find the options of the first line of the `authorized_keys` file at `path` that lists
+/// `key` and allows `peer`, or `None` if there is none.
+///
+/// The file is read on every call, so changes take effect immediately. Lines with options that
+/// are not supported are skipped with a warning.
+pub async fn find(path: &Path, key: &PublicKey, peer: Option<IpAddr>) -> Option<Options> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) => {
+            tracing::warn!(
+                "Failed to read authorized keys from {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    let mut found = None;
+    for (number, line) in contents.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(entry)) if found.is_none() && entry.key == *key => {
+                if entry.options.allows(peer) {
+                    found = Some(entry.options);
+                } else {
+                    tracing::info!(
+                        "Key on line {} of {} may not be used from {}",
+                        number + 1,
+                        path.display(),
+                        peer.map_or_else(|| "an unknown address".to_string(), |ip| ip.to_string())
+                    );
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Skipping line {} of {}: {}", number + 1, path.display(), e),
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFE+eY99s1Vzhj/pv3lwqJvzg5DzdslkDGRs98ORoryf";
+
+    fn options(line: &str) -> Result<Options, String> {
+        parse_line(&format!("{} {} comment", line, KEY)).map(|entry| entry.unwrap().options)
+    }
+
+    #[test]
+    fn parses_lines_without_options() {
+        let entry = parse_line(&format!("{} me@host", KEY)).unwrap().unwrap();
+        assert_eq!(entry.options, Options::default());
+        assert!(parse_line("# comment").unwrap().is_none());
+        assert!(parse_line("ssh-ed25519 invalid").unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_quoted_options() {
+        let parsed = options(r#"no-pty,command="echo \"a, b\" c",from="10.0.0.0/8,!10.1.*""#);
+        assert_eq!(
+            parsed.unwrap(),
+            Options {
+                command: Some(r#"echo "a, b" c"#.to_string()),
+                from: Some(vec!["10.0.0.0/8".to_string(), "!10.1.*".to_string()]),
+                no_pty: true,
+                ..Options::default()
+            }
+        );
+    }
+
+    #[test]
+    fn restrict_can_be_relaxed() {
+        let parsed = options("restrict,pty").unwrap();
+        assert!(!parsed.no_pty);
+        assert!(parsed.no_port_forwarding);
+        assert!(parsed.no_x11_forwarding);
+    }
+
+    #[test]
+    fn rejects_unsupported_options() {
+        assert!(options(r#"permitopen="host:22""#).is_err());
+        assert!(options("cert-authority").is_err());
+        assert!(options(r#"command="unterminated"#).is_err());
+    }
+
+    #[test]
+    fn from_matches_addresses() {
+        let parsed = options(r#"from="10.0.0.0/8,!10.1.*,192.168.1.?""#).unwrap();
+        let allows = |ip: &str| parsed.allows(Some(ip.parse().unwrap()));
+        assert!(allows("10.2.3.4"));
+        assert!(allows("::ffff:10.2.3.4"));
+        assert!(allows("192.168.1.7"));
+        assert!(!allows("10.1.2.3"));
+        assert!(!allows("192.168.1.70"));
+        assert!(!parsed.allows(None));
+        assert!(Options::default().allows(None));
+    }
+
+    #[test]
+    fn keys_setting_refuses_options() {
+        assert!(AllowedKey::try_from(KEY.to_string()).is_ok());
+        assert!(AllowedKey::try_from(format!("no-pty {}", KEY)).is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_lines_with_unsupported_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+        let key = parse_line(KEY).unwrap().unwrap().key;
+        let peer = Some("127.0.0.1".parse().unwrap());
+        std::fs::write(&path, format!("tunnel=\"0\" {}\n", KEY)).unwrap();
+        assert_eq!(find(&path, &key, peer).await, None);
+        std::fs::write(
+            &path,
+            format!("tunnel=\"0\" {k}\nfrom=\"10.*\" {k}\nno-pty {k}\n", k = KEY),
+        )
+        .unwrap();
+        let found = find(&path, &key, peer).await.unwrap();
+        assert!(found.no_pty);
+    }
+}
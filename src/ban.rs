@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Consecutive authentication failures of a single source address.
+#[derive(Debug)]
+pub struct FailureState {
+    failures: u32,
+    last_failure: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// When to ban a source address after failed authentication attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// Number of consecutive failures after which an address is banned. `0` disables banning.
+    pub max_failures: u32,
+
+    /// How long a ban lasts. Failures older than this are forgotten as well.
+    pub ban_duration: Duration,
+}
+
+impl Policy {
+    /// Check whether `ip` is currently banned, forgetting expired state.
+    pub fn is_banned(&self, failures: &mut HashMap<IpAddr, FailureState>, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        match failures.get(&ip) {
+            Some(FailureState {
+                banned_until: Some(until),
+                ..
+            }) if *until > now => true,
+            Some(state) if self.is_expired(state, now) => {
+                failures.remove(&ip);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Record a failed authentication attempt from `ip`.
+    pub fn record_failure(&self, failures: &mut HashMap<IpAddr, FailureState>, ip: IpAddr) {
+        if self.max_failures == 0 {
+            return;
+        }
+        let now = Instant::now();
+        failures.retain(|_, state| !self.is_expired(state, now));
+        let state = failures.entry(ip).or_insert(FailureState {
+            failures: 0,
+            last_failure: now,
+            banned_until: None,
+        });
+        state.failures += 1;
+        state.last_failure = now;
+        if state.failures >= self.max_failures && state.banned_until.is_none() {
+            tracing::warn!(
+                "Banning {} for {}s after {} failed authentication attempts",
+                ip,
+                self.ban_duration.as_secs(),
+                state.failures
+            );
+            state.banned_until = Some(now + self.ban_duration);
+        }
+    }
+
+    /// Record a successful authentication from `ip`, resetting its failure count.
+    pub fn record_success(&self, failures: &mut HashMap<IpAddr, FailureState>, ip: IpAddr) {
+        failures.remove(&ip);
+    }
+
+    fn is_expired(&self, state: &FailureState, now: Instant) -> bool {
+        match state.banned_until {
+            Some(until) => until <= now,
+            None => now.duration_since(state.last_failure) >= self.ban_duration,
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{Gid, Pid, Uid};
+use russh::server::Handle;
+use russh::{ChannelId, Sig};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::exec::Exec;
+use crate::forward::Forward;
+use crate::idle::IdleTimer;
+use crate::lifetime::LifetimeTimer;
+use crate::rlimits::Limits;
+use crate::sftp::Sftp;
+use crate::shell::{PtyRequest, Shell};
+use crate::throttle::Throttle;
+use crate::x11::Display;
+
+/// State of the open channels of all connections, by connection id and channel.
+pub type Channels = Arc<Mutex<HashMap<(usize, ChannelId), ChannelState>>>;
+
+/// State of a single session channel.
+#[derive(Default)]
+pub struct ChannelState {
+    /// Pseudo-terminal requested by the client, if any.
+    pub pty: Option<PtyRequest>,
+
+    /// Environment variables set by the client and accepted by `accept_env`.
+    pub env: Vec<(String, String)>,
+
+    /// Closes the channel if the client stays silent for too long.
+    pub idle: Option<IdleTimer>,
+
+    /// Closes the channel once it has been open for too long.
+    pub lifetime: Option<LifetimeTimer>,
+
+    /// X11 display forwarded to the client, if requested.
+    pub x11: Option<Display>,
+
+    /// Data transferred on the channel so far.
+    pub traffic: Arc<Traffic>,
+
+    /// Process running on this channel, if any.
+    pub process: Option<Process>,
+
+    /// Delivers the chat messages of other connections, in the `chat` mode.
+    pub chat: Option<AbortOnDrop>,
+}
+
+/// Where and as whom to run the processes of a user.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Login {
+    /// Working directory.
+    pub home: Option<PathBuf>,
+
+    /// User and group id to switch to in the child process.
+    ///
+    /// Supplementary groups are dropped. The server process itself keeps its privileges.
+    pub ids: Option<(u32, u32)>,
+
+    /// Directory to make the root of the child process, checked by [`check_jail`].
+    pub chroot: Option<PathBuf>,
+
+    /// Resource limits of the child process.
+    pub rlimits: Limits,
+}
+
+impl Login {
+    /// Apply the working directory, ids and limits to a command run through pipes.
+    pub fn configure(&self, command: &mut tokio::process::Command) {
+        if self.chroot.is_some() {
+            // Safety: the closure only makes system calls.
+            unsafe { command.pre_exec(self.jail()) };
+            return;
+        }
+        if let Some(home) = &self.home {
+            command.current_dir(home);
+        }
+        if let Some((uid, gid)) = self.ids {
+            command.uid(uid).gid(gid);
+        }
+        if !self.rlimits.is_empty() {
+            // Safety: the closure only makes system calls.
+            unsafe { command.pre_exec(self.limit()) };
+        }
+    }
+
+    /// Apply the working directory, ids and limits to a command run on a pseudo-terminal.
+    pub fn configure_pty(&self, command: &mut pty_process::Command) {
+        if self.chroot.is_some() {
+            // Safety: the closure only makes system calls.
+            unsafe { command.pre_exec(self.jail()) };
+            return;
+        }
+        if let Some(home) = &self.home {
+            command.current_dir(home);
+        }
+        if let Some((uid, gid)) = self.ids {
+            command.uid(uid).gid(gid);
+        }
+        if !self.rlimits.is_empty() {
+            // Safety: the closure only makes system calls.
+            unsafe { command.pre_exec(self.limit()) };
+        }
+    }
+
+    /// Switch the current process to the working directory, ids and limits, entering the
+    /// chroot first if there is one.
+    ///
+    /// This is for processes that start as the server's user and drop privileges themselves,
+    /// like the SFTP server, which cannot be executed from within the chroot.
+    pub fn enter(&self) -> std::io::Result<()> {
+        if self.chroot.is_some() {
+            return self.jail()();
+        }
+        if let Some(home) = &self.home {
+            nix::unistd::chdir(home)?;
+        }
+        if let Some((uid, gid)) = self.ids {
+            nix::unistd::setgroups(&[])?;
+            nix::unistd::setgid(Gid::from_raw(gid))?;
+            nix::unistd::setuid(Uid::from_raw(uid))?;
+        }
+        self.rlimits.apply()?;
+        Ok(())
+    }
+
+    /// Apply the resource limits in the child.
+    fn limit(&self) -> impl FnMut() -> std::io::Result<()> + Send + Sync + 'static {
+        let rlimits = self.rlimits;
+        move || Ok(rlimits.apply()?)
+    }
+
+    /// Enter the chroot in the child, then switch to the working directory and ids and apply
+    /// the limits.
+    ///
+    /// Commands switch ids before running such a closure, after which `chroot` would fail,
+    /// so the ids are switched here as well.
+    fn jail(&self) -> impl FnMut() -> std::io::Result<()> + Send + Sync + 'static {
+        let chroot = self.chroot.clone().unwrap_or_else(|| PathBuf::from("/"));
+        let home = self.home.clone();
+        let ids = self.ids;
+        let rlimits = self.rlimits;
+        move || {
+            nix::unistd::chroot(&chroot)?;
+            let entered = match &home {
+                Some(home) => nix::unistd::chdir(home).is_ok(),
+                None => false,
+            };
+            if !entered {
+                nix::unistd::chdir("/")?;
+            }
+            if let Some((uid, gid)) = ids {
+                nix::unistd::setgroups(&[])?;
+                nix::unistd::setgid(Gid::from_raw(gid))?;
+                nix::unistd::setuid(Uid::from_raw(uid))?;
+            }
+            rlimits.apply()?;
+            Ok(())
+        }
+    }
+}
+
+/// Check that `path` and all of its parents are owned by root and writable only by root, so
+/// that a user in the chroot cannot replace anything in it, e.g. to gain privileges.
+pub fn check_jail(path: &Path) -> anyhow::Result<()> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", path.display()))?;
+    for dir in path.ancestors() {
+        let metadata =
+            std::fs::metadata(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+        if !metadata.is_dir() {
+            anyhow::bail!("{} is not a directory", dir.display());
+        }
+        if metadata.uid() != 0 {
+            anyhow::bail!("{} is not owned by root", dir.display());
+        }
+        if metadata.mode() & 0o022 != 0 {
+            anyhow::bail!("{} is writable by others than root", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// A process started by a `shell`, `exec` or `subsystem` request, or a forwarded connection.
+#[derive(Clone)]
+pub enum Process {
+    /// Process attached to a pseudo-terminal.
+    Pty(Shell),
+
+    /// Process attached to pipes.
+    Pipes(Exec),
+
+    /// Built-in SFTP server, running in a child process.
+    Sftp(Sftp),
+
+    /// TCP connection of a `direct-tcpip` or `x11` channel.
+    Forward(Forward),
+}
+
+impl Process {
+    /// Send input to the process.
+    pub async fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Process::Pty(shell) => shell.write(data).await,
+            Process::Pipes(exec) => exec.write(data).await,
+            Process::Sftp(sftp) => sftp.write(data).await,
+            Process::Forward(forward) => forward.write(data).await,
+        }
+    }
+
+    /// Pass on that the client will not send any more data.
+    ///
+    /// Terminals have no end of input, clients send `^D` as data instead.
+    pub async fn eof(&self) -> std::io::Result<()> {
+        match self {
+            Process::Pty(_) => Ok(()),
+            Process::Pipes(exec) => {
+                exec.close_stdin().await;
+                Ok(())
+            }
+            Process::Sftp(sftp) => sftp.close_input().await,
+            Process::Forward(forward) => forward.shutdown().await,
+        }
+    }
+
+    /// Send `signal` to the process, if this is one.
+    pub fn signal(&self, signal: Signal) -> nix::Result<()> {
+        match self {
+            Process::Pty(shell) => shell.signal(signal),
+            Process::Pipes(exec) => exec.signal(signal),
+            Process::Sftp(_) | Process::Forward(_) => Ok(()),
+        }
+    }
+}
+
+/// Counts the channel data transferred in each direction.
+pub struct Traffic {
+    opened: Instant,
+    received: AtomicU64,
+    sent: AtomicU64,
+    /// Limits the bandwidth of data sent to the client, if set.
+    throttle: Option<Throttle>,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Traffic::new(None)
+    }
+}
+
+impl Traffic {
+    /// Start counting, sending at most `bandwidth_limit` bytes per second if set.
+    pub fn new(bandwidth_limit: Option<u64>) -> Self {
+        Traffic {
+            opened: Instant::now(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            throttle: bandwidth_limit.map(Throttle::new),
+        }
+    }
+
+    /// Count `len` bytes received from the client.
+    pub fn add_received(&self, len: usize) {
+        self.received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count `len` bytes sent to the client.
+    pub fn add_sent(&self, len: usize) {
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count `len` bytes about to be sent to the client, waiting until the bandwidth limit
+    /// allows them.
+    pub async fn pace_sent(&self, len: usize) {
+        self.add_sent(len);
+        if let Some(throttle) = &self.throttle {
+            throttle.take(len).await;
+        }
+    }
+
+    /// Bytes received from the client.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent to the client.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Time since the channel was opened.
+    pub fn elapsed(&self) -> Duration {
+        self.opened.elapsed()
+    }
+}
+
+/// Process group of a child process, forgotten once the child was reaped so that a reused id
+/// is never signalled.
+#[derive(Clone, Default)]
+pub struct ProcessGroup(Arc<std::sync::Mutex<Option<Pid>>>);
+
+impl ProcessGroup {
+    /// The group led by the process with id `pid`, if it was started.
+    pub fn new(pid: Option<u32>) -> Self {
+        let pid = pid.map(|pid| Pid::from_raw(pid as i32));
+        ProcessGroup(Arc::new(std::sync::Mutex::new(pid)))
+    }
+
+    /// Send `signal` to every process in the group, if the leader was not reaped yet.
+    pub fn signal(&self, signal: Signal) -> nix::Result<()> {
+        match *self.0.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(pid) => killpg(pid, signal),
+            None => Ok(()),
+        }
+    }
+
+    /// Record that the leader was reaped.
+    pub fn reaped(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// A background task that is cancelled when dropped.
+pub struct AbortOnDrop(pub JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Report how a process exited on `channel`, then close the channel.
+///
+/// A process killed by a signal is reported with `exit-signal` instead of `exit-status`.
+pub async fn finish(handle: &Handle, channel: ChannelId, status: std::io::Result<ExitStatus>) {
+    let exit_status = match status {
+        Ok(status) => match status.signal() {
+            Some(signal) => {
+                let _ = handle
+                    .exit_signal_request(
+                        channel,
+                        sig(signal),
+                        status.core_dumped(),
+                        String::new(),
+                        "en".to_string(),
+                    )
+                    .await;
+                None
+            }
+            None => Some(status.code().unwrap_or(1)),
+        },
+        Err(e) => {
+            tracing::warn!("Failed to wait for child process: {}", e);
+            Some(1)
+        }
+    };
+    if let Some(exit_status) = exit_status {
+        let _ = handle
+            .exit_status_request(channel, exit_status as u32)
+            .await;
+    }
+    let _ = handle.eof(channel).await;
+    let _ = handle.close(channel).await;
+}
+
+/// Convert an SSH signal name into a signal, see RFC 4254, section 6.10.
+pub fn signal(sig: &Sig) -> Option<Signal> {
+    let name = match sig {
+        Sig::ABRT => "ABRT",
+        Sig::ALRM => "ALRM",
+        Sig::FPE => "FPE",
+        Sig::HUP => "HUP",
+        Sig::ILL => "ILL",
+        Sig::INT => "INT",
+        Sig::KILL => "KILL",
+        Sig::PIPE => "PIPE",
+        Sig::QUIT => "QUIT",
+        Sig::SEGV => "SEGV",
+        Sig::TERM => "TERM",
+        Sig::USR1 => "USR1",
+        Sig::Custom(name) => name,
+    };
+    format!("SIG{}", name).parse().ok()
+}
+
+/// Convert a signal number into its SSH name, see RFC 4254, section 6.10.
+fn sig(signal: i32) -> Sig {
+    match Signal::try_from(signal) {
+        Ok(Signal::SIGABRT) => Sig::ABRT,
+        Ok(Signal::SIGALRM) => Sig::ALRM,
+        Ok(Signal::SIGFPE) => Sig::FPE,
+        Ok(Signal::SIGHUP) => Sig::HUP,
+        Ok(Signal::SIGILL) => Sig::ILL,
+        Ok(Signal::SIGINT) => Sig::INT,
+        Ok(Signal::SIGKILL) => Sig::KILL,
+        Ok(Signal::SIGPIPE) => Sig::PIPE,
+        Ok(Signal::SIGQUIT) => Sig::QUIT,
+        Ok(Signal::SIGSEGV) => Sig::SEGV,
+        Ok(Signal::SIGTERM) => Sig::TERM,
+        Ok(Signal::SIGUSR1) => Sig::USR1,
+        Ok(other) => Sig::Custom(other.as_str().trim_start_matches("SIG").to_string()),
+        Err(_) => Sig::Custom(signal.to_string()),
+    }
+}
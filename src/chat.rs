@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use tokio::sync::broadcast;
+use tracing::Instrument;
+
+use crate::channel::{AbortOnDrop, Traffic};
+
+/// Number of posts a channel may fall behind before it misses some.
+const CAPACITY: usize = 1024;
+
+#[derive(Clone)]
+struct Post {
+    from: usize,
+    data: CryptoVec,
+}
+
+/// The chat room of the `chat` mode.
+///
+/// Posting never waits for the readers, so a client that does not read its channel only
+/// delays itself.
+#[derive(Clone)]
+pub struct Chat(broadcast::Sender<Post>);
+
+impl Chat {
+    pub fn new() -> Self {
+        Chat(broadcast::channel(CAPACITY).0)
+    }
+
+    /// Send `data` to the session channels of every connection but `from`.
+    pub fn post(&self, from: usize, data: CryptoVec) {
+        // Without any channels in the chat there is no one to tell.
+        let _ = self.0.send(Post { from, data });
+    }
+
+    /// Deliver the posts of other connections to `channel` of `connection` until dropped,
+    /// counted in and limited by its `traffic`.
+    pub fn join(
+        &self,
+        connection: usize,
+        channel: ChannelId,
+        handle: Handle,
+        traffic: Arc<Traffic>,
+    ) -> AbortOnDrop {
+        let mut posts = self.0.subscribe();
+        let deliver = async move {
+            loop {
+                let post = match posts.recv().await {
+                    Ok(post) => post,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Channel {:?} missed {} chat messages", channel, missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if post.from == connection {
+                    continue;
+                }
+                traffic.pace_sent(post.data.len()).await;
+                if handle.data(channel, post.data).await.is_err() {
+                    break;
+                }
+            }
+        };
+        AbortOnDrop(tokio::spawn(deliver.in_current_span()))
+    }
+}
@@ -0,0 +1,28 @@
+/// Characters that let a shell run more than the requested program.
+const SHELL_METACHARACTERS: &[char] = &[';', '&', '|', '`', '$', '(', ')', '<', '>', '\n', '\r'];
+
+/// Check whether `command` of an `exec` request matches any of the `allowed_commands` patterns.
+///
+/// Patterns may contain `*` and `?` wildcards and are matched against the program name, the
+/// first word of the command, or against the whole command if they contain a space. Commands
+/// with shell metacharacters are never allowed, as they could run other programs.
+///
+/// Commands are run by a shell, whose parsing this only approximates, so patterns should be
+/// as narrow as possible.
+pub fn allowed(patterns: &[String], command: &str) -> bool {
+    if command.contains(SHELL_METACHARACTERS) {
+        return false;
+    }
+    let command = command.trim();
+    let program = match command.split_whitespace().next() {
+        Some(program) => program.trim_matches(['\'', '"']),
+        None => return false,
+    };
+    patterns.iter().any(|pattern| {
+        if pattern.contains(' ') {
+            crate::glob_match(pattern, command)
+        } else {
+            crate::glob_match(pattern, program)
+        }
+    })
+}
@@ -0,0 +1,135 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use russh::ChannelId;
+use serde::{Serialize, Serializer};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+use crate::audit::Outcome;
+
+/// Number of events a subscriber may fall behind before it is dropped.
+const CAPACITY: usize = 1024;
+
+/// How long writing an event to a subscriber may take before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened on the server, as sent to subscribers of the event stream.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    ConnectionOpened {
+        connection: usize,
+        peer: Option<SocketAddr>,
+    },
+    ConnectionClosed {
+        connection: usize,
+    },
+    Auth {
+        connection: usize,
+        user: &'a str,
+        method: &'a str,
+        outcome: Outcome,
+    },
+    ChannelOpened {
+        connection: usize,
+        #[serde(serialize_with = "channel_number")]
+        channel: ChannelId,
+        /// The channel type, like `session` or `direct-tcpip`.
+        kind: &'a str,
+    },
+    ChannelClosed {
+        connection: usize,
+        #[serde(serialize_with = "channel_number")]
+        channel: ChannelId,
+        received: u64,
+        sent: u64,
+    },
+}
+
+fn channel_number<S: Serializer>(channel: &ChannelId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u32(channel.to_string().parse().unwrap_or_default())
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Sends events to the subscribers of the event stream, if it is enabled.
+#[derive(Clone, Default)]
+pub struct Events(Option<broadcast::Sender<Arc<str>>>);
+
+impl Events {
+    pub fn new() -> Self {
+        Events(Some(broadcast::channel(CAPACITY).0))
+    }
+
+    /// Send `event` to all subscribers, without waiting for any of them.
+    pub fn send(&self, event: Event<'_>) {
+        let Some(sender) = &self.0 else {
+            return;
+        };
+        if sender.receiver_count() == 0 {
+            return;
+        }
+        let line = Line {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event: &event,
+        };
+        match serde_json::to_string(&line) {
+            Ok(line) => {
+                let _ = sender.send(format!("{}\n", line).into());
+            }
+            Err(e) => tracing::warn!("Failed to serialize event: {}", e),
+        }
+    }
+
+    /// Stream the events as JSON lines to every client connecting to `listener`.
+    ///
+    /// Clients falling more than [`CAPACITY`] events behind, or not reading for
+    /// [`WRITE_TIMEOUT`], are disconnected. Slow clients never hold up the server.
+    pub async fn serve(self, listener: TcpListener) {
+        let Some(sender) = self.0 else {
+            return;
+        };
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept event stream connection: {}", e);
+                    tokio::time::sleep(crate::listener::ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let mut receiver = sender.subscribe();
+            tracing::debug!("Streaming events to {}", peer);
+            tokio::spawn(async move {
+                loop {
+                    let line = match receiver.recv().await {
+                        Ok(line) => line,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            tracing::warn!(
+                                "Dropping event stream client {}, which missed {} events",
+                                peer,
+                                missed
+                            );
+                            break;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    let write =
+                        tokio::time::timeout(WRITE_TIMEOUT, stream.write_all(line.as_bytes()));
+                    if !matches!(write.await, Ok(Ok(()))) {
+                        tracing::debug!("Stopped streaming events to {}", peer);
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::process::Stdio;
+use std::sync::Arc;
+
+use nix::sys::signal::Signal;
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::ChildStdin;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+use crate::channel;
+
+/// Extended data type of stderr, see RFC 4254, section 5.2.
+pub const EXTENDED_DATA_STDERR: u32 = 1;
+
+/// Exit status reported when the command could not be started, like a shell does.
+const EXIT_STATUS_NOT_RUN: u32 = 127;
+
+/// A command attached to a channel through pipes.
+///
+/// The command and everything it started are hung up once the last clone is dropped while the
+/// command is still running.
+#[derive(Clone)]
+pub struct Exec {
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+    group: channel::ProcessGroup,
+    _task: Arc<channel::AbortOnDrop>,
+}
+
+impl Exec {
+    /// Run `command` through `wrapper` (e.g. `sh -c`) for `login`, with the additional
+    /// environment `env`.
+    ///
+    /// Stdout and stderr are sent to `channel` until the command exits, after which the exit
+    /// status is reported and the channel is closed. If the command cannot be started, the error
+    /// is reported on stderr together with a non-zero exit status.
+    pub fn spawn(
+        wrapper: &[String],
+        command: &str,
+        env: &[(String, String)],
+        login: &channel::Login,
+        channel: ChannelId,
+        handle: Handle,
+        traffic: Arc<channel::Traffic>,
+    ) -> Self {
+        let mut process = tokio::process::Command::new(&wrapper[0]);
+        process
+            .args(&wrapper[1..])
+            .arg(command)
+            .envs(env.iter().cloned())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true);
+        login.configure(&mut process);
+
+        let mut child = match process.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                tracing::warn!("Failed to run {:?}: {}", command, e);
+                let message = format!("russh-server: failed to run {}: {}\r\n", wrapper[0], e);
+                let report = tokio::spawn(async move {
+                    let _ = handle
+                        .extended_data(channel, EXTENDED_DATA_STDERR, CryptoVec::from(message))
+                        .await;
+                    let _ = handle
+                        .exit_status_request(channel, EXIT_STATUS_NOT_RUN)
+                        .await;
+                    let _ = handle.eof(channel).await;
+                    let _ = handle.close(channel).await;
+                });
+                return Exec {
+                    stdin: Arc::new(Mutex::new(None)),
+                    group: channel::ProcessGroup::default(),
+                    _task: Arc::new(channel::AbortOnDrop(report)),
+                };
+            }
+        };
+
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let group = channel::ProcessGroup::new(child.id());
+        let hang_up = HangUpOnDrop(group.clone());
+        let run = async move {
+            tokio::join!(
+                forward(stdout, None, channel, &handle, &traffic),
+                forward(
+                    stderr,
+                    Some(EXTENDED_DATA_STDERR),
+                    channel,
+                    &handle,
+                    &traffic
+                ),
+            );
+            let status = child.wait().await;
+            hang_up.0.reaped();
+            channel::finish(&handle, channel, status).await;
+        };
+        let run = tokio::spawn(run.in_current_span());
+
+        Exec {
+            stdin: Arc::new(Mutex::new(stdin)),
+            group,
+            _task: Arc::new(channel::AbortOnDrop(run)),
+        }
+    }
+
+    /// Send input to the command.
+    pub async fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        match self.stdin.lock().await.as_mut() {
+            Some(stdin) => stdin.write_all(data).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Close the standard input of the command, after the client sent EOF.
+    pub async fn close_stdin(&self) {
+        self.stdin.lock().await.take();
+    }
+
+    /// Send `signal` to the command and everything it started.
+    pub fn signal(&self, signal: Signal) -> nix::Result<()> {
+        self.group.signal(signal)
+    }
+}
+
+/// Sends SIGHUP to a process group when dropped, unless its leader was reaped.
+struct HangUpOnDrop(channel::ProcessGroup);
+
+impl Drop for HangUpOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.signal(Signal::SIGHUP);
+    }
+}
+
+/// Copy everything from `reader` to `channel`, as extended data of type `ext` if given.
+async fn forward<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    ext: Option<u32>,
+    channel: ChannelId,
+    handle: &Handle,
+    traffic: &channel::Traffic,
+) {
+    let mut reader = match reader {
+        Some(reader) => reader,
+        None => return,
+    };
+    let mut buffer = [0; 4096];
+    loop {
+        let n = match reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        traffic.pace_sent(n).await;
+        let data = CryptoVec::from_slice(&buffer[..n]);
+        let sent = match ext {
+            Some(ext) => handle.extended_data(channel, ext, data).await,
+            None => handle.data(channel, data).await,
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+}
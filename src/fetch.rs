@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper::client::conn::http1;
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+
+/// How long fetching a configuration may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest configuration accepted, in bytes.
+const MAX_SIZE: usize = 1024 * 1024;
+
+/// Whether `location` is a URL to fetch instead of a path.
+pub fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Fetch the text at the `http://` URL `url`.
+///
+/// HTTPS is not supported, so this is meant for services on the same host or network, like a
+/// sidecar of a secret manager.
+pub async fn get(url: &str) -> anyhow::Result<String> {
+    tokio::time::timeout(TIMEOUT, fetch(url))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "fetching {} did not finish within {}s",
+                url,
+                TIMEOUT.as_secs()
+            )
+        })?
+        .with_context(|| format!("failed to fetch {}", url))
+}
+
+async fn fetch(url: &str) -> anyhow::Result<String> {
+    let uri: Uri = url.parse()?;
+    if uri.scheme_str() != Some("http") {
+        anyhow::bail!("only http:// URLs are supported");
+    }
+    let host = uri.host().context("the URL has no host")?;
+    let port = uri.port_u16().unwrap_or(80);
+    let authority = uri.authority().context("the URL has no host")?.clone();
+    let stream = TcpStream::connect((host, port)).await?;
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    let request = Request::get(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(hyper::header::HOST, authority.as_str())
+        .header(hyper::header::USER_AGENT, env!("CARGO_PKG_NAME"))
+        .body(Empty::<Bytes>::new())?;
+    let response = sender.send_request(request).await?;
+    if !response.status().is_success() {
+        anyhow::bail!("the server answered {}", response.status());
+    }
+    let body = Limited::new(response.into_body(), MAX_SIZE)
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to read the response: {}", e))?
+        .to_bytes();
+    String::from_utf8(body.to_vec()).context("the response is not UTF-8")
+}
@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+use crate::channel::{AbortOnDrop, Traffic};
+
+/// How long to wait for the target of a forwarding to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A TCP connection attached to a `direct-tcpip`, `forwarded-tcpip` or `x11` channel.
+///
+/// The connection is closed once the last clone is dropped.
+#[derive(Clone)]
+pub struct Forward {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    _reader: Arc<AbortOnDrop>,
+}
+
+impl Forward {
+    /// Connect to `host` and `port`, sending everything received from there to `channel`.
+    ///
+    /// The channel is closed when the target closes the connection.
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        channel: ChannelId,
+        handle: Handle,
+        traffic: Arc<Traffic>,
+    ) -> std::io::Result<Self> {
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")
+            })??;
+        Ok(Forward::attach(stream, channel, handle, traffic))
+    }
+
+    /// Send everything received from `stream` to `channel`, closing the channel when the
+    /// peer closes the connection.
+    pub fn attach(
+        stream: TcpStream,
+        channel: ChannelId,
+        handle: Handle,
+        traffic: Arc<Traffic>,
+    ) -> Self {
+        let (mut reader, writer) = stream.into_split();
+        let forward = async move {
+            let mut buffer = [0; 4096];
+            loop {
+                match reader.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        traffic.pace_sent(n).await;
+                        if handle
+                            .data(channel, CryptoVec::from_slice(&buffer[..n]))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        };
+        let reader = tokio::spawn(forward.in_current_span());
+        Forward {
+            writer: Arc::new(Mutex::new(writer)),
+            _reader: Arc::new(AbortOnDrop(reader)),
+        }
+    }
+
+    /// Send data from the client to the target.
+    pub async fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.lock().await.write_all(data).await
+    }
+
+    /// Shut down the sending half of the connection, after the client sent EOF.
+    pub async fn shutdown(&self) -> std::io::Result<()> {
+        self.writer.lock().await.shutdown().await
+    }
+}
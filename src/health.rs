@@ -0,0 +1,63 @@
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+/// Whether the server accepts connections, as reported to health checks.
+#[derive(Clone, Default)]
+pub struct Health {
+    /// Set once the server listens on all its addresses.
+    pub listening: Arc<AtomicBool>,
+    /// Set once the server is shutting down.
+    pub shutting_down: Arc<AtomicBool>,
+}
+
+impl Health {
+    /// Answer every HTTP request on `listener` with 200 while the server accepts connections,
+    /// and 503 before it listens and while it shuts down.
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept health check connection: {}", e);
+                    tokio::time::sleep(crate::listener::ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let health = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(|_| {
+                    let response = health.respond();
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    tracing::debug!("Health check connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn respond(&self) -> Response<Full<Bytes>> {
+        let (status, body) = if self.shutting_down.load(Ordering::SeqCst) {
+            (StatusCode::SERVICE_UNAVAILABLE, "shutting down\n")
+        } else if !self.listening.load(Ordering::SeqCst) {
+            (StatusCode::SERVICE_UNAVAILABLE, "starting\n")
+        } else {
+            (StatusCode::OK, "ok\n")
+        };
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        *response.status_mut() = status;
+        response
+    }
+}
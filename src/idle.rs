@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use tracing::Instrument;
+
+use crate::channel::AbortOnDrop;
+
+/// Closes a channel once the client has not sent any data for a while.
+///
+/// The timer stops when dropped.
+pub struct IdleTimer {
+    last_input: Arc<Mutex<Instant>>,
+    expired: Arc<AtomicBool>,
+    interactive: Arc<AtomicBool>,
+    _task: AbortOnDrop,
+}
+
+impl IdleTimer {
+    /// Close `channel` after `timeout` without a call to [`IdleTimer::touch`].
+    ///
+    /// Once the channel is [interactive](IdleTimer::interactive), the client is told `warning`
+    /// before the timeout that the channel is going to be closed.
+    pub fn start(
+        timeout: Duration,
+        warning: Option<Duration>,
+        channel: ChannelId,
+        handle: Handle,
+    ) -> Self {
+        let last_input = Arc::new(Mutex::new(Instant::now()));
+        let expired = Arc::new(AtomicBool::new(false));
+        let interactive = Arc::new(AtomicBool::new(false));
+        let watch = {
+            let last_input = last_input.clone();
+            let expired = expired.clone();
+            let interactive = interactive.clone();
+            let handle = handle.clone();
+            async move {
+                // The input after which the client was warned, so it is warned once per pause.
+                let mut warned_after = None;
+                loop {
+                    let last = *last_input.lock().unwrap_or_else(|e| e.into_inner());
+                    let deadline = last + timeout;
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    let warn_at = match warning {
+                        Some(warning) if warned_after != Some(last) => deadline - warning,
+                        _ => deadline,
+                    };
+                    if now < warn_at {
+                        tokio::time::sleep_until(warn_at.into()).await;
+                        continue;
+                    }
+                    warned_after = Some(last);
+                    if interactive.load(Ordering::SeqCst) {
+                        let remaining = (deadline - now).as_secs_f64().ceil();
+                        let message = format!(
+                            "\r\nThis session is idle and will be closed in {}s.\r\n",
+                            remaining
+                        );
+                        let _ = handle.data(channel, CryptoVec::from(message)).await;
+                    }
+                }
+                tracing::info!(
+                    "Closing channel {:?} after {}s without input",
+                    channel,
+                    timeout.as_secs()
+                );
+                expired.store(true, Ordering::SeqCst);
+                let _ = handle.eof(channel).await;
+                let _ = handle.close(channel).await;
+            }
+        };
+        IdleTimer {
+            last_input,
+            expired,
+            interactive,
+            _task: AbortOnDrop(tokio::spawn(watch.in_current_span())),
+        }
+    }
+
+    /// Record input from the client, restarting the timeout.
+    pub fn touch(&self) {
+        *self.last_input.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Record that the channel has a pseudo-terminal, so the client sees the warning.
+    pub fn interactive(&self) {
+        self.interactive.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the channel was closed because of the timeout.
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
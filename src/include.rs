@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// Extension of the files included from a directory.
+const EXTENSION: &str = "toml";
+
+/// The files matched by the `include` entry `pattern`, sorted by name.
+///
+/// `pattern` is either a directory, standing for all `.toml` files in it, or a path whose last
+/// component may contain `*` and `?` wildcards. Relative paths are resolved against `base`,
+/// the directory of the including file. A pattern without wildcards must match a file.
+pub fn resolve(base: &Path, pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let path = base.join(pattern);
+    let (dir, name) = if path.is_dir() {
+        (path.as_path(), None)
+    } else {
+        let name = path.file_name().and_then(|name| name.to_str());
+        match (path.parent(), name) {
+            (Some(dir), Some(name)) if name.contains(['*', '?']) => {
+                // The parent of a bare file name is empty.
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                (dir, Some(name))
+            }
+            _ => return Ok(vec![path]),
+        }
+    };
+    if dir.to_str().is_some_and(|dir| dir.contains(['*', '?'])) {
+        anyhow::bail!("wildcards are only supported in the file name");
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read directory {}", dir.display()))?
+    {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        let matches = match name {
+            Some(name) => crate::glob_match(name, file_name),
+            // Skips hidden files, e.g. editor backups.
+            None => {
+                !file_name.starts_with('.')
+                    && Path::new(file_name).extension() == Some(EXTENSION.as_ref())
+            }
+        };
+        if matches && entry.path().is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
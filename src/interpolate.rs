@@ -0,0 +1,63 @@
+use anyhow::Context;
+use toml::Value;
+
+/// Replace `${NAME}` in every string of `value` with the environment variable `NAME`.
+///
+/// `$${` stands for a literal `${`. Referencing a variable that is not set is an error.
+pub fn expand(value: &mut Value) -> anyhow::Result<()> {
+    expand_at(value, "")
+}
+
+fn expand_at(value: &mut Value, path: &str) -> anyhow::Result<()> {
+    match value {
+        Value::String(s) => {
+            *s = expand_str(s).with_context(|| format!("failed to expand {}", path))?;
+        }
+        Value::Array(values) => {
+            for (index, value) in values.iter_mut().enumerate() {
+                expand_at(value, &format!("{}[{}]", path, index))?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                expand_at(value, &path)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_str(s: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start]);
+            expanded.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').context("unterminated ${")?;
+        let name = &rest[start + 2..start + end];
+        let value = match std::env::var(name) {
+            Ok(value) => value,
+            Err(std::env::VarError::NotPresent) => {
+                anyhow::bail!("environment variable {} is not set", name)
+            }
+            Err(std::env::VarError::NotUnicode(_)) => {
+                anyhow::bail!("environment variable {} is not valid UTF-8", name)
+            }
+        };
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
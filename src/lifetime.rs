@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use russh::server::Handle;
+use russh::ChannelId;
+use tracing::Instrument;
+
+use crate::channel::AbortOnDrop;
+
+/// Closes a channel once it has been open for a while, no matter whether it is in use.
+///
+/// The timer stops when dropped.
+pub struct LifetimeTimer {
+    expired: Arc<AtomicBool>,
+    _task: AbortOnDrop,
+}
+
+impl LifetimeTimer {
+    /// Close `channel` after `limit`.
+    pub fn start(limit: Duration, channel: ChannelId, handle: Handle) -> Self {
+        let expired = Arc::new(AtomicBool::new(false));
+        let watch = {
+            let expired = expired.clone();
+            async move {
+                tokio::time::sleep(limit).await;
+                tracing::info!(
+                    "Closing channel {:?} after reaching the session limit of {}s",
+                    channel,
+                    limit.as_secs()
+                );
+                expired.store(true, Ordering::SeqCst);
+                let _ = handle.eof(channel).await;
+                let _ = handle.close(channel).await;
+            }
+        };
+        LifetimeTimer {
+            expired,
+            _task: AbortOnDrop(tokio::spawn(watch.in_current_span())),
+        }
+    }
+
+    /// Whether the channel was closed because of the limit.
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
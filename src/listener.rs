@@ -0,0 +1,302 @@
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{self, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use listenfd::ListenFd;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::oneshot;
+
+/// A byte stream a session can run on.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A stream that fails once its [`Sever`] is used, ending the session running on it.
+///
+/// russh keeps running a session after its future is dropped, so this is the only way to end
+/// one the client does not close.
+pub struct Severable {
+    stream: Box<dyn Stream>,
+    /// `None` once the stream is severed or cannot be anymore.
+    severed: Option<oneshot::Receiver<()>>,
+    broken: bool,
+}
+
+/// Severs a [`Severable`] stream.
+pub struct Sever(oneshot::Sender<()>);
+
+impl Severable {
+    pub fn new(stream: Box<dyn Stream>) -> (Self, Sever) {
+        let (sender, receiver) = oneshot::channel();
+        let severable = Severable {
+            stream,
+            severed: Some(receiver),
+            broken: false,
+        };
+        (severable, Sever(sender))
+    }
+
+    /// Whether the stream was severed, checked before every operation.
+    fn poll_severed(&mut self, cx: &mut task::Context<'_>) -> bool {
+        if let Some(severed) = &mut self.severed {
+            match Pin::new(severed).poll(cx) {
+                Poll::Ready(Ok(())) => {
+                    self.severed = None;
+                    self.broken = true;
+                }
+                Poll::Ready(Err(_)) => self.severed = None,
+                Poll::Pending => {}
+            }
+        }
+        self.broken
+    }
+}
+
+impl Sever {
+    pub fn sever(self) {
+        let _ = self.0.send(());
+    }
+}
+
+fn severed() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection severed")
+}
+
+impl AsyncRead for Severable {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.poll_severed(cx) {
+            return Poll::Ready(Err(severed()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Severable {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.poll_severed(cx) {
+            return Poll::Ready(Err(severed()));
+        }
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.poll_severed(cx) {
+            return Poll::Ready(Err(severed()));
+        }
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// A socket accepting connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixSocket),
+}
+
+/// A Unix domain socket listener.
+pub struct UnixSocket {
+    listener: UnixListener,
+    /// Socket file to remove when dropped, unless someone else created it.
+    path: Option<PathBuf>,
+}
+
+/// How long to wait before accepting again after an error, e.g. when out of file descriptors,
+/// so the loop neither spins nor floods the log.
+pub const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether `SO_REUSEPORT` can be set on this platform.
+pub const REUSE_PORT_SUPPORTED: bool = cfg!(not(any(
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "cygwin"
+)));
+
+/// Options of TCP listening sockets.
+pub struct BindOptions {
+    /// Maximum number of connections waiting to be accepted.
+    pub backlog: u32,
+
+    /// Whether to set `SO_REUSEADDR`, allowing a restarted server to listen right away.
+    pub reuse_address: bool,
+
+    /// Whether to set `SO_REUSEPORT`, letting several servers listen on the same port.
+    pub reuse_port: bool,
+}
+
+/// Options of accepted TCP connections.
+pub struct StreamOptions {
+    /// Whether to set `TCP_NODELAY`, sending small packets without delay.
+    pub nodelay: bool,
+
+    /// TCP keepalive probes to send, if any.
+    pub keepalive: Option<TcpKeepalive>,
+}
+
+/// A connection accepted by a [`Listener`].
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: SocketAddr, options: &BindOptions) -> anyhow::Result<Self> {
+        let bind = || -> std::io::Result<TcpListener> {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            socket.set_reuse_address(options.reuse_address)?;
+            #[cfg(not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin")))]
+            if options.reuse_port {
+                socket.set_reuse_port(true)?;
+            }
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(options.backlog.try_into().unwrap_or(i32::MAX))?;
+            TcpListener::from_std(socket.into())
+        };
+        let listener = bind().with_context(|| format!("failed to listen on {}", addr))?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    /// Listen on a Unix domain socket at `path`, replacing a stale socket left behind there.
+    pub fn bind_unix(path: &Path) -> anyhow::Result<Self> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                anyhow::bail!("{} exists and is not a socket", path.display());
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                anyhow::bail!("{} is in use by another server", path.display());
+            }
+            std::fs::remove_file(path)
+                .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to listen on {}", path.display()))?;
+        Ok(Listener::Unix(UnixSocket {
+            listener,
+            path: Some(path.to_path_buf()),
+        }))
+    }
+
+    /// Take the sockets passed by systemd socket activation, if any.
+    pub fn inherited() -> anyhow::Result<Vec<Self>> {
+        let mut fds = ListenFd::from_env();
+        let mut listeners = Vec::new();
+        for index in 0..fds.len() {
+            if let Ok(Some(listener)) = fds.take_tcp_listener(index) {
+                listener.set_nonblocking(true)?;
+                listeners.push(Listener::Tcp(TcpListener::from_std(listener)?));
+                continue;
+            }
+            let listener = fds
+                .take_unix_listener(index)
+                .with_context(|| format!("unsupported socket {} passed by systemd", index))?;
+            if let Some(listener) = listener {
+                listener.set_nonblocking(true)?;
+                listeners.push(Listener::Unix(UnixSocket {
+                    listener: UnixListener::from_std(listener)?,
+                    path: None,
+                }));
+            }
+        }
+        Ok(listeners)
+    }
+
+    pub async fn accept(&self) -> std::io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => Ok(Connection::Tcp(listener.accept().await?.0)),
+            Listener::Unix(socket) => Ok(Connection::Unix(socket.listener.accept().await?.0)),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "unknown address"),
+            },
+            Listener::Unix(socket) => match socket.listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => write!(f, "{}", path.display()),
+                    None => write!(f, "unnamed socket"),
+                },
+                Err(_) => write!(f, "unknown address"),
+            },
+        }
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl Connection {
+    /// Apply `options` to a TCP connection.
+    pub fn configure(&self, options: &StreamOptions) -> std::io::Result<()> {
+        let Connection::Tcp(stream) = self else {
+            return Ok(());
+        };
+        if options.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = &options.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(keepalive)?;
+        }
+        Ok(())
+    }
+
+    /// Address of the client, unknown for Unix domain sockets.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            Connection::Unix(_) => None,
+        }
+    }
+
+    /// Address the client connected to, unknown for Unix domain sockets.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.local_addr().ok(),
+            Connection::Unix(_) => None,
+        }
+    }
+
+    pub fn into_stream(self) -> Box<dyn Stream> {
+        match self {
+            Connection::Tcp(stream) => Box::new(stream),
+            Connection::Unix(stream) => Box::new(stream),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Context;
+
+/// How long the login script may run before the session is refused.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most of the script's stderr to show the client.
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Run the login script at `path` with the additional environment `env`.
+///
+/// Returns `Ok(None)` if the script allows the session, or the message to show the client if
+/// it exits with a non-zero status. Scripts that cannot start or time out are errors.
+pub async fn run(path: &Path, env: &[(String, String)]) -> anyhow::Result<Option<Vec<u8>>> {
+    let child = tokio::process::Command::new(path)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run login script {}", path.display()))?;
+    let output = tokio::time::timeout(TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "login script {} did not finish within {}s",
+                path.display(),
+                TIMEOUT.as_secs()
+            )
+        })?
+        .with_context(|| format!("failed to run login script {}", path.display()))?;
+    if output.status.success() {
+        return Ok(None);
+    }
+    tracing::info!(
+        "Login script {} refused the session: {}",
+        path.display(),
+        output.status
+    );
+    let mut message = output.stderr;
+    message.truncate(MAX_MESSAGE_LENGTH);
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn passes_the_connection_to_the_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("login");
+        std::fs::write(
+            &path,
+            "#!/bin/sh\necho \"$SSH_CLIENT|$SSH_CONNECTION\" >&2\nexit 1\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let env = crate::connection_env(
+            Some("192.0.2.1:50000".parse().unwrap()),
+            Some("[2001:db8::1]:22".parse().unwrap()),
+        );
+        let message = run(&path, &env).await.unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "192.0.2.1 50000 22|192.0.2.1 50000 2001:db8::1 22\n"
+        );
+    }
+}
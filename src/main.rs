@@ -1,18 +1,72 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use anyhow::Context;
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use russh::server::{Msg, Session};
+use ipnet::IpNet;
+use russh::server::{Msg, Response, Session};
+use russh::Pty;
 use russh::*;
 use russh_keys::*;
-use tokio::sync::Mutex;
+use socket2::TcpKeepalive;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::Instrument;
+use tracing_subscriber::filter::LevelFilter;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+mod admin;
+mod algorithms;
+mod audit;
+mod auth_command;
+mod authorized_keys;
+mod ban;
+mod channel;
+mod chat;
+mod command_filter;
+mod events;
+mod exec;
+mod fetch;
+mod forward;
+mod health;
+mod host_key;
+mod idle;
+mod include;
+mod interpolate;
+mod lifetime;
+mod listener;
+mod login_script;
+mod message;
+mod metrics;
+mod password;
+mod permissions;
+mod proxy_protocol;
+mod recording;
+mod refuse;
+mod remote_forward;
+mod rlimits;
+mod sftp;
+mod shell;
+mod systemd;
+mod terminal;
+mod throttle;
+mod totp;
+mod x11;
 
 /// Simple SSH server written in Rust.
 ///
@@ -34,9 +88,48 @@ struct Args {
     #[arg(short, long)]
     port: Option<u16>,
 
-    /// Path to configuration file.
+    /// Path to configuration file, `-` to read it from stdin, or an `http://` URL to fetch it
+    /// from.
     #[arg(short, long, default_value = "russh-server.toml")]
     config_file: PathBuf,
+
+    /// Only warn about a config file or host keys that others may access, instead of refusing
+    /// them.
+    #[arg(long)]
+    insecure: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Read a password from stdin and print its hash for use as `password_hash`.
+    HashPassword,
+
+    /// Generate a host key for use as `host_key` and print its public key.
+    GenerateHostKey {
+        /// Where to write the private key, in the OpenSSH format.
+        path: PathBuf,
+
+        /// Replace an existing file at `path`.
+        #[arg(long)]
+        overwrite: bool,
+
+        /// Algorithm of the key.
+        #[arg(long, value_enum, default_value_t)]
+        algorithm: host_key::Algorithm,
+    },
+
+    /// Check the configuration file and host keys, then exit without starting the server.
+    CheckConfig,
+
+    /// Serve SFTP on stdin and stdout, as started by the server for every SFTP session.
+    #[command(hide = true)]
+    SftpServer {
+        /// The settings chosen by the server, as JSON.
+        settings: String,
+    },
 }
 
 fn default_address() -> String {
@@ -47,182 +140,4172 @@ fn default_port() -> u16 {
     22
 }
 
+fn default_exec_wrapper() -> Vec<String> {
+    vec!["/bin/sh".to_string(), "-c".to_string()]
+}
+
+fn default_log_level() -> String {
+    "debug".to_string()
+}
+
+fn default_connection_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_login_grace_time_secs() -> u64 {
+    120
+}
+
+fn default_auth_rejection_time_secs() -> u64 {
+    3
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    10
+}
+
+fn default_tcp_backlog() -> u32 {
+    // The default of tokio.
+    1024
+}
+
+fn default_window_size() -> u32 {
+    russh::server::Config::default().window_size
+}
+
+fn default_maximum_packet_size() -> u32 {
+    russh::server::Config::default().maximum_packet_size
+}
+
+fn default_so_reuseaddr() -> bool {
+    true
+}
+
+fn default_password_auth_enabled() -> bool {
+    true
+}
+
+fn default_keepalive_count_max() -> u32 {
+    3
+}
+
+fn default_max_auth_failures() -> u32 {
+    10
+}
+
+fn default_max_auth_tries() -> u32 {
+    6
+}
+
+fn default_ban_duration_secs() -> u64 {
+    300
+}
+
+/// What a session without a shell, command or subsystem does with the data it receives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    /// Send the data to every other session as well, like a chat room.
+    ///
+    /// Lines are prefixed with the nickname of the sender, and everyone is told when someone
+    /// joins or leaves. Shell requests are accepted without starting a shell, and commands are
+    /// refused unless the user has a `forced_command`.
+    Chat,
+
+    /// Send the data back to the client.
+    ///
+    /// Shell requests are accepted without starting a shell, and commands are refused unless the
+    /// user has a `forced_command`.
+    Echo,
+
+    /// Discard the data; shell requests start the user's login shell and commands are run.
+    #[default]
+    Shell,
+}
+
+/// Authentication methods the server implements.
+const SUPPORTED_METHODS: MethodSet = MethodSet::PASSWORD
+    .union(MethodSet::PUBLICKEY)
+    .union(MethodSet::KEYBOARD_INTERACTIVE);
+
+/// An authentication method a user may be restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AuthMethod {
+    Password,
+    Publickey,
+    KeyboardInteractive,
+}
+
+impl AuthMethod {
+    fn method_set(self) -> MethodSet {
+        match self {
+            AuthMethod::Password => MethodSet::PASSWORD,
+            AuthMethod::Publickey => MethodSet::PUBLICKEY,
+            AuthMethod::KeyboardInteractive => MethodSet::KEYBOARD_INTERACTIVE,
+        }
+    }
+}
+
+/// Output format of log messages.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+
+    /// One JSON object per line, including the fields of the enclosing connection.
+    Json,
+}
+
+/// The configuration file.
+///
+/// `${NAME}` in any string value is replaced with the environment variable `NAME` when the
+/// file is read.
 #[derive(Debug, Deserialize)]
 struct ConfigFile {
     /// Use a fixed host key.
     ///
-    /// This is a serialized ed25519 key pair from the `ed25519-dalek` crate. See
-    /// <https://docs.rs/ed25519-dalek/1.0.1/ed25519_dalek/struct.Keypair.html> for details.
+    /// Kept for backward compatibility, this is equivalent to a single entry in `host_keys`.
     host_key: Option<PathBuf>,
 
+    /// Use fixed host keys.
+    ///
+    /// Each entry is either a private key in the OpenSSH format (e.g.
+    /// `/etc/ssh/ssh_host_ed25519_key`) or a serialized ed25519 key pair from the `ed25519-dalek`
+    /// crate. See <https://docs.rs/ed25519-dalek/1.0.1/ed25519_dalek/struct.Keypair.html> for
+    /// details. At most one key per algorithm may be given. RSA keys require building with the
+    /// `openssl` feature.
+    #[serde(default)]
+    host_keys: Vec<PathBuf>,
+
+    /// Where to keep a generated host key if neither `host_key` nor `host_keys` is set.
+    ///
+    /// The key is generated on the first start and loaded from there afterwards. Without this,
+    /// a new host key is generated on every start.
+    host_key_auto_path: Option<PathBuf>,
+
+    /// Algorithm of generated host keys: `ed25519`, `rsa-2048`, `rsa-4096` or `ecdsa-p256`.
+    ///
+    /// RSA keys require building with the `openssl` feature, and ECDSA keys are not supported
+    /// yet.
+    #[serde(default)]
+    host_key_algorithm: host_key::Algorithm,
+
     /// Address to bind to.
+    ///
+    /// Ignored if `listen` is given.
     #[serde(default = "default_address")]
     address: String,
 
     /// Port number to listen on.
+    ///
+    /// Ignored if `listen` is given.
     #[serde(default = "default_port")]
     port: u16,
 
-    // User configuration.
+    /// Addresses and ports to listen on, e.g. `[{ address = "0.0.0.0", port = 22 }]`, or Unix
+    /// domain sockets like `{ unix = "/run/russh-server.sock" }`.
+    ///
+    /// Defaults to `address` and `port`.
+    #[serde(default)]
+    listen: Vec<Listen>,
+
+    /// Expect a PROXY protocol header (version 1 or 2) on every connection, as sent by load
+    /// balancers, and take the client address from it.
+    ///
+    /// Connections without a valid header are closed.
+    #[serde(default)]
+    proxy_protocol: bool,
+
+    /// Seconds of inactivity after which a connection is closed.
+    ///
+    /// Set to `0` to disable the timeout.
+    #[serde(default = "default_connection_timeout_secs")]
+    connection_timeout_secs: u64,
+
+    /// Seconds a client has to authenticate, like OpenSSH's `LoginGraceTime`. Connections
+    /// that have not authenticated by then are closed, even if they are not idle.
+    ///
+    /// Set to `0` to disable the limit.
+    #[serde(default = "default_login_grace_time_secs")]
+    login_grace_time_secs: u64,
+
+    /// Seconds to wait before answering a failed authentication attempt.
+    #[serde(default = "default_auth_rejection_time_secs")]
+    auth_rejection_time_secs: u64,
+
+    /// Whether anyone may log in with a password, over `password` or `keyboard-interactive`.
+    ///
+    /// Set to `false` to allow public keys only, whatever `auth_methods` of the users say.
+    #[serde(default = "default_password_auth_enabled")]
+    password_auth_enabled: bool,
+
+    /// Number of consecutive failed authentication attempts after which a source address is
+    /// banned.
+    ///
+    /// Set to `0` to disable banning.
+    #[serde(default = "default_max_auth_failures")]
+    max_auth_failures: u32,
+
+    /// Number of failed authentication attempts after which a connection is dropped, like
+    /// `MaxAuthTries` of OpenSSH.
+    ///
+    /// Set to `0` to allow any number of attempts.
+    #[serde(default = "default_max_auth_tries")]
+    max_auth_tries: u32,
+
+    /// How long a source address stays banned, in seconds.
+    #[serde(default = "default_ban_duration_secs")]
+    ban_duration_secs: u64,
+
+    /// Program and arguments used to run commands of `exec` requests.
+    ///
+    /// The requested command is appended as the last argument.
+    #[serde(default = "default_exec_wrapper")]
+    exec_wrapper: Vec<String>,
+
+    /// How long to wait for clients to disconnect after SIGTERM or SIGINT, in seconds.
+    #[serde(default = "default_shutdown_grace_secs")]
+    shutdown_grace_secs: u64,
+
+    /// Close channels on which the client sent no data for this many seconds.
+    ///
+    /// The client is disconnected once it confirms closing the channel. `0` disables the
+    /// timeout.
+    #[serde(default)]
+    idle_timeout_secs: u64,
+
+    /// Tell clients this many seconds before `idle_timeout_secs` that their channel is going
+    /// to be closed, so they can keep it open by typing something.
+    ///
+    /// Only channels with a pseudo-terminal are warned, as the warning would end up in the
+    /// output of other channels. `0` disables the warning.
+    #[serde(default)]
+    idle_warning_secs: u64,
+
+    /// Close channels once they have been open for this many seconds, even if they are in use.
+    ///
+    /// The client is disconnected once it confirms closing the channel. `0` disables the
+    /// limit.
+    #[serde(default)]
+    max_session_secs: u64,
+
+    /// Send at most this many bytes per second to the client on each channel. `0` disables
+    /// the limit.
+    #[serde(default)]
+    max_bandwidth_bytes_per_sec: u64,
+
+    /// Send TCP keepalive probes after this many seconds without traffic, so that NAT and
+    /// firewalls keep the connection open. `0` disables keepalives.
+    ///
+    /// russh cannot send SSH-level keepalives, so this uses the operating system's TCP keepalive.
+    #[serde(default)]
+    keepalive_interval_secs: u64,
+
+    /// Number of unanswered keepalive probes after which the connection is dropped.
+    #[serde(default = "default_keepalive_count_max")]
+    keepalive_count_max: u32,
+
+    /// Send small packets right away instead of collecting them, for snappier interactive
+    /// sessions at the cost of more packets.
+    #[serde(default)]
+    tcp_nodelay: bool,
+
+    /// Maximum number of TCP connections waiting to be accepted.
+    #[serde(default = "default_tcp_backlog")]
+    tcp_backlog: u32,
+
+    /// Listen even if connections to the same address are still shutting down, e.g. right
+    /// after a restart.
+    #[serde(default = "default_so_reuseaddr")]
+    so_reuseaddr: bool,
+
+    /// Let several servers listen on the same address and port, with the kernel spreading
+    /// new connections between them, e.g. to run one server per CPU core.
+    ///
+    /// Every server must set this. They share nothing but the port, so each needs its own
+    /// `admin_socket`, `metrics_addr` and `health_addr`, and limits like `max_connections`,
+    /// `max_sessions` and bans apply to each server separately. Give them the same
+    /// `host_key`, or clients will see the host key change between connections.
+    ///
+    /// Sets `SO_REUSEPORT`, and may also be written as `so_reuseport`.
+    #[serde(default, alias = "so_reuseport")]
+    reuse_port: bool,
+
+    /// Bytes clients may send on a channel before waiting for the server to catch up.
+    ///
+    /// Larger windows speed up transfers over links with a high latency, at the cost of
+    /// buffering more data per channel.
+    #[serde(default = "default_window_size")]
+    window_size: u32,
+
+    /// Largest data packet clients may send on a channel, in bytes.
+    ///
+    /// At most 65535 and at most `window_size`.
+    #[serde(default = "default_maximum_packet_size")]
+    maximum_packet_size: u32,
+
+    /// Maximum number of concurrent sessions per user.
+    ///
+    /// Unlimited by default.
+    max_sessions: Option<usize>,
+
+    /// Maximum number of channels a client may have open at once on one connection, counting
+    /// sessions and `ssh -L` forwards. Further channels are refused.
+    ///
+    /// Clients multiplexing several sessions over one connection, like OpenSSH with
+    /// `ControlMaster`, share this limit between all of them. Unlimited by default.
+    max_channels_per_connection: Option<usize>,
+
+    /// Maximum number of concurrent connections that have not authenticated yet, like OpenSSH's
+    /// `MaxStartups`. Further connections are closed right away.
+    ///
+    /// Unlimited by default.
+    max_unauthenticated: Option<usize>,
+
+    /// Reasons given to clients when disconnecting them, e.g. `shutdown = "Back in 5 minutes"`
+    /// in a `[messages]` table.
+    ///
+    /// The keys are `shutdown`, `idle_timeout`, `session_limit`, `kicked`,
+    /// `too_many_connections` and `banned`.
+    #[serde(default)]
+    messages: Messages,
+
+    /// Maximum number of concurrent connections, authenticated or not.
+    ///
+    /// Further clients are disconnected right away with a message saying there are too many
+    /// connections. Unlimited by default.
+    max_connections: Option<usize>,
+
+    /// File to append a JSON line to for every authentication attempt.
+    audit_log: Option<PathBuf>,
+
+    /// Whether to log the full public key offered in every public key authentication attempt,
+    /// not just its fingerprint, e.g. to add unknown keys to `keys`.
+    #[serde(default)]
+    log_keys: bool,
+
+    /// Address to serve Prometheus metrics on at `/metrics`, e.g. `"127.0.0.1:9100"`.
+    ///
+    /// Metrics are not served by default.
+    metrics_addr: Option<SocketAddr>,
+
+    /// Address to answer HTTP health checks on, e.g. `"0.0.0.0:8080"` for Kubernetes probes.
+    ///
+    /// Any request is answered with 200 while the server accepts connections, and with 503
+    /// while it starts or shuts down. Health checks are not served by default.
+    health_addr: Option<SocketAddr>,
+
+    /// Address to stream events to monitoring clients on, e.g. `"127.0.0.1:9000"`.
+    ///
+    /// Every client connecting to it receives one JSON object per line for each opened and
+    /// closed connection and channel and each authentication attempt, from then on. Clients
+    /// that fall behind are disconnected. Events are not streamed by default.
+    event_stream_addr: Option<SocketAddr>,
+
+    /// Unix domain socket to answer admin commands on, e.g. listing and kicking connections.
+    ///
+    /// Only the owner of the server process may connect. See `admin::serve` for the commands.
+    admin_socket: Option<PathBuf>,
+
+    /// Environment variables clients may set, e.g. `["LANG", "LC_*"]`.
+    ///
+    /// `*` matches any number of characters and `?` matches a single one.
+    #[serde(default)]
+    accept_env: Vec<String>,
+
+    /// Behavior of sessions, either `"shell"`, `"echo"` or `"chat"`.
+    #[serde(default)]
+    mode: Mode,
+
+    /// Whether clients may open connections from the server with `ssh -L`.
+    #[serde(default)]
+    allow_local_forward: bool,
+
+    /// Targets clients may connect to with `ssh -L`, as `"host:port"`.
+    ///
+    /// Empty means any target.
+    #[serde(default)]
+    local_forward_targets: Vec<String>,
+
+    /// Whether clients may have the server listen for connections to forward with `ssh -R`.
+    ///
+    /// The client chooses the address to listen on, e.g. `ssh -R 0.0.0.0:8080:localhost:80`
+    /// accepts connections from anywhere.
+    #[serde(default)]
+    allow_remote_forward: bool,
+
+    /// Ports clients may listen on with `ssh -R`.
+    ///
+    /// Empty means any port from 1024 on, including one chosen by the server. Lower ports are
+    /// only allowed when listed.
+    #[serde(default)]
+    remote_forward_ports: Vec<u16>,
+
+    /// Whether clients may forward X11 with `ssh -X`.
+    ///
+    /// Displays listen on localhost, starting at `localhost:10`.
+    #[serde(default)]
+    allow_x11_forwarding: bool,
+
+    /// Refuse legacy algorithms, currently SHA-1 RSA (`ssh-rsa`) user keys.
+    #[serde(default)]
+    modern_crypto: bool,
+
+    /// Algorithms of user keys to refuse, e.g. `["ssh-rsa"]`.
+    ///
+    /// Defaults to `["ssh-rsa"]` with `modern_crypto` and to none otherwise.
+    disallowed_key_algorithms: Option<Vec<String>>,
+
+    /// Key exchange methods to offer, most preferred first, e.g. `["curve25519-sha256@libssh.org"]`.
+    ///
+    /// This and the other algorithm lists default to everything russh implements.
+    #[serde(default)]
+    kex: Vec<String>,
+
+    /// Ciphers to offer, most preferred first, e.g. `["aes256-gcm@openssh.com"]`.
+    #[serde(default)]
+    ciphers: Vec<String>,
+
+    /// MACs to offer for ciphers without integrated authentication, e.g. `["hmac-sha2-256"]`.
+    #[serde(default)]
+    macs: Vec<String>,
+
+    /// Compression methods to offer, e.g. `["none"]`.
+    #[serde(default)]
+    compression: Vec<String>,
+
+    /// Output format of log messages, either `"text"` or `"json"`.
+    #[serde(default)]
+    log_format: LogFormat,
+
+    /// Most verbose level of log messages, e.g. `"info"` or `"debug"`.
+    #[serde(default = "default_log_level")]
+    log_level: String,
+
+    /// Software version announced to clients as `SSH-2.0-<server_id>`, instead of the one of
+    /// russh.
+    ///
+    /// Comments may follow after a space, e.g. `"Acme_1.0 internal"`.
+    server_id: Option<String>,
+
+    /// Banner shown to clients before they authenticate, e.g. a legal notice.
+    ///
+    /// Either the text itself or `{ file = "/path/to/banner" }`.
+    banner: Option<message::Message>,
+
+    /// Message of the day, shown when a user starts a shell.
+    ///
+    /// Either the text itself or `{ file = "/etc/motd" }`, which is re-read for every session.
+    motd: Option<message::Message>,
+
+    /// Program run by the server whenever a user starts a shell, e.g. for accounting.
+    ///
+    /// It gets `SSH_USER`, `SSH_CHANNEL` and, for connections over TCP, `SSH_CLIENT` and
+    /// `SSH_CONNECTION` like a shell in its environment. If it exits with a non-zero status or
+    /// does not finish within 10 seconds, the shell is refused and whatever it wrote to stderr
+    /// is shown to the client.
+    login_script: Option<PathBuf>,
+
+    /// Program asked about `password`, `publickey` and `keyboard-interactive` attempts that the
+    /// configured credentials of the user do not accept, e.g. to check passwords against LDAP.
+    ///
+    /// It gets a JSON object with the `user`, `method`, `peer` and the `password` or
+    /// `public_key` on stdin, and accepts the attempt by exiting with status 0. Only public
+    /// keys are passed, as private keys never leave the client. For `keyboard-interactive` it
+    /// may print `{"result": "partial", "prompt": "Verification code: "}` to ask the client
+    /// another question, and is then run again with the answers in `responses`. It rejects
+    /// by exiting with another status, printing `{"result": "reject"}`, failing or taking
+    /// longer than 10 seconds.
+    ///
+    /// The users still need an entry in `users` for their settings, possibly `"*"`.
+    auth_command: Option<PathBuf>,
+
+    /// Directory to record the output of terminal sessions to, in the ttyrec format that
+    /// `ttyplay` replays. Each session gets a file named after the user, the start time and
+    /// the connection and channel ids. Sessions are refused if their recording cannot be
+    /// created.
+    ///
+    /// Recordings hold everything the terminal shows, including secrets printed by commands
+    /// and input that is echoed, so keep the directory private and let users know they are
+    /// recorded. Commands run without a pseudo-terminal, SFTP and forwarding are not recorded.
+    session_recording_dir: Option<PathBuf>,
+
+    /// Users, either as `[users.alice]` tables or as `[[users]]` entries with a `name`.
+    ///
+    /// A user named `"*"` matches any user name without an entry of its own, e.g. for
+    /// honeypots or users checked by `auth_command`. Never configure one otherwise.
+    #[serde(default, deserialize_with = "deserialize_users")]
     users: HashMap<String, UserConfig>,
-}
 
-#[derive(Debug, Deserialize)]
-struct UserConfig {
-    password: Option<String>,
+    /// File to read the users from instead of `users`, keeping credentials out of this file.
+    ///
+    /// It holds what `users` would, i.e. `[alice]` tables in TOML, or an object or array in
+    /// JSON if its name ends with `.json`. Relative paths are resolved against the directory of
+    /// this file. Others must not have access to it, while this file may then be readable by
+    /// anyone.
+    users_file: Option<PathBuf>,
 
+    /// Files to read more users from, e.g. `["users.d"]` or `["/etc/russh/users/*.toml"]`.
+    ///
+    /// Each entry is either a directory, standing for all `.toml` files in it, or a path whose
+    /// file name may contain `*` and `?` wildcards. Relative paths are resolved against the
+    /// directory of this file. Included files may only contain `users`, and a user must not be
+    /// configured in more than one file.
     #[serde(default)]
-    keys: Vec<String>,
-}
+    include: Vec<String>,
 
-#[tokio::main]
-async fn main() {
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Debug)
-        .init();
+    /// User names that can never log in, whatever their credentials, e.g. `["root", "admin"]`.
+    ///
+    /// Entries may contain `*` and `?` wildcards. Denied users are rejected like users with
+    /// wrong credentials, so clients cannot tell them apart. This wins over `allowed_users`.
+    #[serde(default)]
+    denied_users: Vec<String>,
 
-    let args = Args::parse();
-    let config_file: ConfigFile =
-        toml::from_str(&std::fs::read_to_string(args.config_file).unwrap()).unwrap();
-
-    let host_key = if let Some(path) = &config_file.host_key {
-        log::debug!("Reading host key from {}...", path.display());
-        let mut f = File::open(path).unwrap();
-        let mut buffer: [u8; 64] = [0; 64];
-        f.read_exact(&mut buffer).unwrap();
-        let kp = ed25519_dalek::Keypair::from_bytes(&buffer).unwrap();
-        russh_keys::key::KeyPair::Ed25519(kp)
-    } else {
-        log::debug!("Generating new host key...");
-        russh_keys::key::KeyPair::generate_ed25519().unwrap()
-    };
+    /// User names that may log in, like `denied_users`. Empty means anyone configured in
+    /// `users`.
+    #[serde(default)]
+    allowed_users: Vec<String>,
 
-    let config = Arc::new(russh::server::Config {
-        connection_timeout: Some(std::time::Duration::from_secs(3600)),
-        auth_rejection_time: std::time::Duration::from_secs(3),
-        auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-        keys: vec![host_key],
-        ..Default::default()
-    });
-    let sh = Server {
-        clients: Arc::new(Mutex::new(HashMap::new())),
-        users: Arc::new(config_file.users),
-        id: 0,
-    };
+    /// Files read because of `users_file` or `include`.
+    #[serde(skip)]
+    included: Vec<PathBuf>,
+}
 
-    let address = args.address.unwrap_or(config_file.address);
-    let port = args.port.unwrap_or(config_file.port);
-    log::info!("Listening on {}:{}...", address, port);
-    russh::server::run(config, (address, port), sh)
-        .await
-        .unwrap();
+/// The contents of `users_file`.
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct UsersFile {
+    #[serde(deserialize_with = "deserialize_users")]
+    users: HashMap<String, UserConfig>,
 }
 
-#[derive(Clone)]
-struct Server {
-    clients: Arc<Mutex<HashMap<(usize, ChannelId), russh::server::Handle>>>,
-    users: Arc<HashMap<String, UserConfig>>,
-    id: usize,
+/// A file read because of `include`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFragment {
+    #[serde(default, deserialize_with = "deserialize_users")]
+    users: HashMap<String, UserConfig>,
 }
 
-impl Server {
-    async fn post(&mut self, data: CryptoVec) {
-        let mut clients = self.clients.lock().await;
-        for ((id, channel), ref mut s) in clients.iter_mut() {
-            if *id != self.id {
-                let _ = s.data(*channel, data.clone()).await;
-            }
+/// Reasons given to clients when disconnecting them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Messages {
+    /// Sent when the server shuts down, and shown in interactive sessions before.
+    shutdown: String,
+
+    /// Sent to clients that were idle for `idle_timeout_secs`.
+    idle_timeout: String,
+
+    /// Sent when a session reached `max_session_secs`.
+    session_limit: String,
+
+    /// Sent to clients kicked on the admin socket without a reason.
+    kicked: String,
+
+    /// Sent to clients beyond `max_connections` or `max_unauthenticated`.
+    too_many_connections: String,
+
+    /// Sent to clients connecting from an address banned after too many failed attempts.
+    banned: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Messages {
+            shutdown: "The server is shutting down".to_string(),
+            idle_timeout: "Idle timeout".to_string(),
+            session_limit: "Session time limit reached".to_string(),
+            kicked: "Disconnected by an administrator".to_string(),
+            too_many_connections: "Too many connections".to_string(),
+            banned: "Too many failed authentication attempts".to_string(),
         }
     }
 }
 
-impl server::Server for Server {
-    type Handler = Self;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-        let s = self.clone();
-        self.id += 1;
-        s
+/// An address and port to accept connections on.
+#[derive(Debug, Clone, Deserialize)]
+struct Listen {
+    /// IPv4 or IPv6 address, optionally in brackets like `[::1]`.
+    ///
+    /// On most systems, `::` accepts IPv4 connections as well.
+    #[serde(default = "default_address")]
+    address: String,
+
+    #[serde(default = "default_port")]
+    port: u16,
+
+    /// Path of a Unix domain socket to listen on instead of `address` and `port`.
+    ///
+    /// A stale socket left at the path is replaced, and the socket is removed on shutdown.
+    unix: Option<PathBuf>,
+}
+
+impl Listen {
+    fn socket_addr(&self) -> Result<SocketAddr, std::net::AddrParseError> {
+        let address = self
+            .address
+            .strip_prefix('[')
+            .and_then(|address| address.strip_suffix(']'))
+            .unwrap_or(&self.address);
+        Ok(SocketAddr::new(address.parse()?, self.port))
     }
 }
 
-#[async_trait]
-impl server::Handler for Server {
-    type Error = anyhow::Error;
+/// Name of the user entry that applies to every user name without an entry of its own.
+const WILDCARD_USER: &str = "*";
 
-    async fn channel_open_session(
-        self,
-        channel: Channel<Msg>,
-        session: Session,
-    ) -> Result<(Self, bool, Session), Self::Error> {
-        {
-            let mut clients = self.clients.lock().await;
-            clients.insert((self.id, channel.id()), session.handle());
+/// An entry of the `[[users]]` array.
+#[derive(Deserialize)]
+struct NamedUser {
+    name: String,
+
+    #[serde(flatten)]
+    config: UserConfig,
+}
+
+/// Deserialize `users` from either a table keyed by name or an array of [`NamedUser`]s.
+fn deserialize_users<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, UserConfig>, D::Error> {
+    struct UsersVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for UsersVisitor {
+        type Value = HashMap<String, UserConfig>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a table of users or an array of users with a name")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            HashMap::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut users = HashMap::new();
+            while let Some(user) = seq.next_element::<NamedUser>()? {
+                match users.entry(user.name) {
+                    Entry::Occupied(entry) => {
+                        return Err(serde::de::Error::custom(format!(
+                            "duplicate user {}",
+                            entry.key()
+                        )))
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(user.config);
+                    }
+                }
+            }
+            Ok(users)
         }
-        Ok((self, true, session))
     }
 
+    deserializer.deserialize_any(UsersVisitor)
+}
+
+#[derive(Debug, Deserialize)]
+struct UserConfig {
+    /// Plaintext password.
+    ///
+    /// Deprecated in favor of `password_hash`.
+    password: Option<String>,
+
+    /// Argon2 password hash in the PHC string format, as printed by `russh-server hash-password`.
+    ///
+    /// Takes precedence over `password`.
+    password_hash: Option<String>,
+
+    /// Base32-encoded secret of a TOTP authenticator app, as a second factor for passwords.
+    ///
+    /// Passwords must then be given over `keyboard-interactive`, which asks for the current
+    /// verification code as well. Public keys are not affected.
+    totp_secret: Option<totp::Secret>,
+
+    /// Keys the user may authenticate with.
+    ///
+    /// Each entry is either a public key line like `ssh-ed25519 AAAA... alice@laptop`, as found
+    /// in `~/.ssh/id_ed25519.pub`, or the SHA256 fingerprint of a key, as printed by
+    /// `ssh-keygen -l`.
+    #[serde(default)]
+    keys: Vec<authorized_keys::AllowedKey>,
+
+    /// OpenSSH `authorized_keys` file listing keys the user may authenticate with.
+    ///
+    /// The file is re-read on every authentication attempt. The `command`, `from`, `restrict`
+    /// and `no-pty`, `no-port-forwarding` and `no-x11-forwarding` options are honored, and lines
+    /// with other options are skipped.
+    authorized_keys: Option<PathBuf>,
+
+    /// Directory served as `/` to SFTP clients.
+    ///
+    /// Defaults to `chroot_dir`, or the home directory of the system user with the same name.
+    /// Files are accessed as `uid` and `gid`, and must be inside of `chroot_dir` if it is set.
+    sftp_root: Option<PathBuf>,
+
+    /// Directory to confine shells and commands to with `chroot`, like OpenSSH's
+    /// `ChrootDirectory`.
+    ///
+    /// The directory and all of its parents must be owned by root and not writable by anyone
+    /// else, and the server must run as root. `home` is then looked up inside of it, falling
+    /// back to `/`.
+    chroot_dir: Option<PathBuf>,
+
+    /// Message of the day for this user, overriding the global `motd`.
+    motd: Option<message::Message>,
+
+    /// Command to run instead of whatever the client requests, e.g. for backups.
+    ///
+    /// This replaces interactive shells as well. The requested command, if any, is available in
+    /// the `SSH_ORIGINAL_COMMAND` environment variable.
+    forced_command: Option<String>,
+
+    /// Whether the user may only use SFTP, like `ForceCommand internal-sftp` of OpenSSH.
+    ///
+    /// Shells and commands are refused. Combine with `chroot_dir` or `sftp_root` to confine the
+    /// user to a directory.
+    #[serde(default)]
+    sftp_only: bool,
+
+    /// Commands the user may run, e.g. `["rsync", "git-receive-pack", "git-upload-pack"]`.
+    ///
+    /// Entries may contain `*` and `?` wildcards and match the program name, or the whole
+    /// command if they contain a space. Commands with shell metacharacters like `;` or `|` are
+    /// refused, as are shells. Checking what a shell will run is best-effort, so prefer
+    /// `forced_command` where a single command suffices. Empty means any command.
+    #[serde(default)]
+    allowed_commands: Vec<String>,
+
+    /// Targets this user may connect to with `ssh -L`, as `"host:port"`, further restricting
+    /// the global `local_forward_targets`.
+    ///
+    /// Entries may contain `*` and `?` wildcards, e.g. `"db.internal:*"`. Empty means any
+    /// target allowed globally.
+    #[serde(default)]
+    permit_open: Vec<String>,
+
+    /// Addresses this user may listen on with `ssh -R`, as `"address:port"`, further
+    /// restricting the global `remote_forward_ports`.
+    ///
+    /// Entries may contain `*` and `?` wildcards, e.g. `"*:8080"` for port 8080 on any
+    /// address. Port `0` is a port chosen by the server. Empty means any port allowed globally.
+    #[serde(default)]
+    permit_listen: Vec<String>,
+
+    /// Resource limits of the shells and commands of this user, e.g.
+    /// `{ cpu_secs = 3600, address_space = 4294967296, processes = 64, open_files = 1024 }`.
+    ///
+    /// Limits are not set by default.
+    #[serde(default)]
+    rlimits: rlimits::Limits,
+
+    /// Maximum number of concurrent sessions of this user, overriding the global `max_sessions`.
+    max_sessions: Option<usize>,
+
+    /// Limit for how long the channels of this user may stay open, overriding the global
+    /// `max_session_secs`. `0` disables the limit.
+    max_session_secs: Option<u64>,
+
+    /// Login shell, also used to run commands instead of the `exec_wrapper`.
+    ///
+    /// Defaults to `$SHELL` of the server, or `/bin/sh`.
+    shell: Option<String>,
+
+    /// Working directory of shells and commands.
+    ///
+    /// Defaults to the home directory of the system user with the same name.
+    home: Option<PathBuf>,
+
+    /// User id to run shells, commands and the SFTP server as, when the server runs as root.
+    ///
+    /// Defaults to the id of the system user with the same name. Without either, the user cannot
+    /// run anything or use SFTP on a server running as root. Only the server process itself
+    /// keeps its privileges.
+    uid: Option<u32>,
+
+    /// Group id to run shells, commands and the SFTP server as, like `uid`.
+    gid: Option<u32>,
+
+    /// Networks the user may log in from, e.g. `"192.168.0.0/16"`.
+    ///
+    /// Empty means any address.
+    #[serde(default)]
+    allow_from: Vec<IpNet>,
+
+    /// Networks the user may not log in from, taking precedence over `allow_from`.
+    #[serde(default)]
+    deny_from: Vec<IpNet>,
+
+    /// Authentication methods the user may use, e.g. `["publickey"]`.
+    ///
+    /// Empty means any method.
+    #[serde(default)]
+    auth_methods: Vec<AuthMethod>,
+}
+
+impl ConfigFile {
+    /// Authentication methods anyone may use, before restrictions of single users.
+    fn auth_methods(&self) -> MethodSet {
+        if self.password_auth_enabled {
+            SUPPORTED_METHODS
+        } else {
+            MethodSet::PUBLICKEY
+        }
+    }
+
+    /// Addresses and ports to listen on, falling back to `address` and `port`.
+    fn listeners(&self) -> Vec<Listen> {
+        if self.listen.is_empty() {
+            vec![Listen {
+                address: self.address.clone(),
+                port: self.port,
+                unix: None,
+            }]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    /// Algorithms of user keys to refuse.
+    fn disallowed_key_algorithms(&self) -> Vec<String> {
+        match &self.disallowed_key_algorithms {
+            Some(algorithms) => algorithms.clone(),
+            None if self.modern_crypto => vec!["ssh-rsa".to_string()],
+            None => Vec::new(),
+        }
+    }
+
+    /// Algorithms to negotiate with clients.
+    fn algorithms(&self) -> algorithms::Selection<'_> {
+        algorithms::Selection {
+            kex: &self.kex,
+            ciphers: &self.ciphers,
+            macs: &self.macs,
+            compression: &self.compression,
+        }
+    }
+
+    /// Check the configuration for mistakes, reporting all of them at once.
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        if let Some(problem) = self.server_id.as_deref().and_then(check_server_id) {
+            problems.push(format!("server_id {}", problem));
+        }
+        problems.extend(self.algorithms().problems());
+        if let Some(reason) = self.host_key_algorithm.unsupported() {
+            problems.push(format!(
+                "host_key_algorithm {}: {}",
+                self.host_key_algorithm.name(),
+                reason
+            ));
+        }
+        if !(MIN_PACKET_SIZE..=MAX_PACKET_SIZE).contains(&self.maximum_packet_size) {
+            problems.push(format!(
+                "maximum_packet_size must be between {} and {}",
+                MIN_PACKET_SIZE, MAX_PACKET_SIZE
+            ));
+        }
+        if self.window_size < self.maximum_packet_size {
+            problems.push("window_size must be at least maximum_packet_size".to_string());
+        }
+        for listen in self.listeners() {
+            if listen.unix.is_some() {
+                continue;
+            }
+            if listen.socket_addr().is_err() {
+                problems.push(format!("address {:?} is not an IP address", listen.address));
+            }
+            if listen.port == 0 {
+                problems.push(format!("port of {} must not be 0", listen.address));
+            }
+        }
+        if self.log_level.parse::<LevelFilter>().is_err() {
+            problems.push(format!("log_level {:?} is not a log level", self.log_level));
+        }
+        if self.reuse_port && !listener::REUSE_PORT_SUPPORTED {
+            problems.push("reuse_port is not supported on this platform".to_string());
+        }
+        if self.idle_warning_secs > 0
+            && (self.idle_timeout_secs == 0 || self.idle_warning_secs >= self.idle_timeout_secs)
+        {
+            problems.push("idle_warning_secs must be less than idle_timeout_secs".to_string());
+        }
+        if self.exec_wrapper.is_empty() {
+            problems.push("exec_wrapper must contain at least the program to run".to_string());
+        }
+        if self.users.is_empty() {
+            problems.push("no users are configured".to_string());
+        }
+        let mut names: Vec<&String> = self.users.keys().collect();
+        names.sort();
+        let enabled = self.auth_methods();
+        for name in names {
+            if self.auth_command.is_none() && !self.users[name].has_credentials(enabled) {
+                problems.push(format!(
+                    "user {} has no credentials for its authentication methods",
+                    name
+                ));
+            }
+            let user = &self.users[name];
+            for problem in user.rlimits.problems() {
+                problems.push(format!("rlimits of user {}: {}", name, problem));
+            }
+            for (setting, entries) in [
+                ("permit_open", &user.permit_open),
+                ("permit_listen", &user.permit_listen),
+            ] {
+                for entry in entries.iter().filter(|entry| !entry.contains(':')) {
+                    problems.push(format!(
+                        "{} entry {:?} of user {} is not of the form host:port",
+                        setting, entry, name
+                    ));
+                }
+            }
+            if user.forced_command.is_some() {
+                if user.sftp_only {
+                    problems.push(format!(
+                        "user {} cannot have both sftp_only and forced_command",
+                        name
+                    ));
+                }
+                if !user.allowed_commands.is_empty() {
+                    problems.push(format!(
+                        "user {} cannot have both allowed_commands and forced_command",
+                        name
+                    ));
+                }
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("invalid configuration:\n  {}", problems.join("\n  "))
+        }
+    }
+}
+
+impl UserConfig {
+    /// Check whether the user has any way to authenticate with the `enabled` methods.
+    fn has_credentials(&self, enabled: MethodSet) -> bool {
+        let methods = self.auth_methods() & enabled;
+        let has_password = self.password.is_some() || self.password_hash.is_some();
+        let has_keys = !self.keys.is_empty() || self.authorized_keys.is_some();
+        (has_password && methods.intersects(MethodSet::PASSWORD | MethodSet::KEYBOARD_INTERACTIVE))
+            || (has_keys && methods.contains(MethodSet::PUBLICKEY))
+    }
+
+    /// Authentication methods the user may use.
+    ///
+    /// Users with a `totp_secret` cannot use `password`, which has no way to ask for the code.
+    fn auth_methods(&self) -> MethodSet {
+        let methods = if self.auth_methods.is_empty() {
+            SUPPORTED_METHODS
+        } else {
+            self.auth_methods
+                .iter()
+                .fold(MethodSet::empty(), |set, method| set | method.method_set())
+        };
+        if self.totp_secret.is_some() {
+            methods - MethodSet::PASSWORD
+        } else {
+            methods
+        }
+    }
+
+    /// Check whether the user may log in from `ip`.
+    fn allows_source(&self, ip: Option<IpAddr>) -> bool {
+        let ip = match ip {
+            Some(ip) => ip.to_canonical(),
+            None => return self.allow_from.is_empty() && self.deny_from.is_empty(),
+        };
+        if self.deny_from.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow_from.is_empty() || self.allow_from.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Directory served to `name` over SFTP, if any.
+    fn sftp_root(&self, name: &str) -> Option<PathBuf> {
+        self.sftp_root
+            .clone()
+            .or_else(|| self.chroot_dir.clone())
+            .or_else(|| system_user(name).map(|user| user.dir))
+    }
+
+    /// Working directory of processes of `name`, if any.
+    fn home(&self, name: &str) -> Option<PathBuf> {
+        self.home
+            .clone()
+            .or_else(|| system_user(name).map(|user| user.dir))
+    }
+
+    /// User and group id of processes of `name`, if known.
+    fn ids(&self, name: &str) -> Option<(u32, u32)> {
+        let system = system_user(name);
+        let uid = self
+            .uid
+            .or_else(|| system.as_ref().map(|user| user.uid.as_raw()))?;
+        let gid = self
+            .gid
+            .or_else(|| system.as_ref().map(|user| user.gid.as_raw()))?;
+        Some((uid, gid))
+    }
+}
+
+/// Longest identification string allowed by RFC 4253, including the line ending.
+const MAX_ID_LENGTH: usize = 255;
+
+/// Smallest `maximum_packet_size` leaving room for data next to the headers of a packet.
+const MIN_PACKET_SIZE: u32 = 1024;
+
+/// Largest `maximum_packet_size` russh accepts, that of a TCP segment.
+const MAX_PACKET_SIZE: u32 = 65535;
+
+/// Check that `SSH-2.0-<server_id>` is a valid identification string, returning the problem
+/// if it is not.
+fn check_server_id(server_id: &str) -> Option<&'static str> {
+    let version = server_id.split(' ').next().unwrap_or_default();
+    if version.is_empty() {
+        Some("must start with a software version")
+    } else if version.contains('-') {
+        Some("must not contain '-' in the software version")
+    } else if !server_id.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        Some("must only contain printable ASCII characters")
+    } else if format!("SSH-2.0-{}\r\n", server_id).len() > MAX_ID_LENGTH {
+        Some("is too long")
+    } else {
+        None
+    }
+}
+
+/// The system user `name`, if there is one.
+fn system_user(name: &str) -> Option<nix::unistd::User> {
+    nix::unistd::User::from_name(name).ok().flatten()
+}
+
+/// Where the configuration file is read from, as given by `--config-file`.
+enum ConfigSource {
+    File(PathBuf),
+    /// The contents of stdin, read once on start.
+    Stdin(String),
+    /// Fetched again on every reload.
+    Url(String),
+}
+
+impl ConfigSource {
+    /// Interpret `--config-file`, reading stdin if it is `-`.
+    fn from_arg(arg: &Path) -> anyhow::Result<Self> {
+        match arg.to_str() {
+            Some("-") => std::io::read_to_string(std::io::stdin())
+                .map(ConfigSource::Stdin)
+                .context("failed to read config file from stdin"),
+            Some(url) if fetch::is_url(url) => Ok(ConfigSource::Url(url.to_string())),
+            _ => Ok(ConfigSource::File(arg.to_path_buf())),
+        }
+    }
+
+    /// The path of the configuration file, if it is a local file.
+    fn path(&self) -> Option<&Path> {
+        match self {
+            ConfigSource::File(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Directory that relative `include`s and the `users_file` are resolved against.
+    ///
+    /// That is the directory of a local file, or the working directory otherwise.
+    fn base(&self) -> &Path {
+        self.path().and_then(Path::parent).unwrap_or(Path::new("."))
+    }
+
+    async fn read(&self) -> anyhow::Result<String> {
+        match self {
+            ConfigSource::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read config file {}", path.display())),
+            ConfigSource::Stdin(contents) => Ok(contents.clone()),
+            ConfigSource::Url(url) => fetch::get(url).await,
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => path.display().fmt(f),
+            ConfigSource::Stdin(_) => f.write_str("stdin"),
+            ConfigSource::Url(url) => f.write_str(url),
+        }
+    }
+}
+
+/// Read and parse the configuration file from `source`, including the users of its
+/// `include`s.
+async fn read_config(source: &ConfigSource) -> anyhow::Result<ConfigFile> {
+    let name = source.to_string();
+    let contents = source.read().await?;
+    let mut config_file: ConfigFile = parse_file(&contents, &name)?;
+    let base = source.base();
+    let mut users_path = PathBuf::from(&name);
+    if let Some(users_file) = &config_file.users_file {
+        if !config_file.users.is_empty() {
+            anyhow::bail!(
+                "{} configures both users and users_file, move the users to {}",
+                name,
+                users_file.display()
+            );
+        }
+        users_path = base.join(users_file);
+        let users: UsersFile = read_file(&users_path)?;
+        config_file.users = users.users;
+        config_file.included.push(users_path.clone());
+    }
+    let mut sources: HashMap<String, PathBuf> = config_file
+        .users
+        .keys()
+        .map(|name| (name.clone(), users_path.clone()))
+        .collect();
+    for pattern in &config_file.include {
+        let files = include::resolve(base, pattern)
+            .with_context(|| format!("failed to resolve include {:?}", pattern))?;
+        for file in files {
+            let fragment: ConfigFragment = read_file(&file)?;
+            for (name, user) in fragment.users {
+                match sources.entry(name) {
+                    Entry::Occupied(entry) => anyhow::bail!(
+                        "user {} is configured in both {} and {}",
+                        entry.key(),
+                        entry.get().display(),
+                        file.display()
+                    ),
+                    Entry::Vacant(entry) => {
+                        config_file.users.insert(entry.key().clone(), user);
+                        entry.insert(file.clone());
+                    }
+                }
+            }
+            config_file.included.push(file);
+        }
+    }
+    config_file
+        .validate()
+        .with_context(|| format!("failed to load config file {}", name))?;
+    Ok(config_file)
+}
+
+/// Read the config file at `path`, see [`parse_file`].
+fn read_file<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    parse_file(&contents, &path.display().to_string())
+}
+
+/// Parse the `contents` of the config file `name` as TOML, or JSON if the name ends with
+/// `.json`, expanding environment variables.
+fn parse_file<T: serde::de::DeserializeOwned>(contents: &str, name: &str) -> anyhow::Result<T> {
+    let value = if name.ends_with(".json") {
+        serde_json::from_str(contents).map_err(anyhow::Error::from)
+    } else {
+        toml::from_str(contents).map_err(anyhow::Error::from)
+    };
+    let mut value: toml::Value =
+        value.with_context(|| format!("failed to parse config file {}", name))?;
+    interpolate::expand(&mut value)
+        .with_context(|| format!("failed to load config file {}", name))?;
+    value
+        .try_into()
+        .with_context(|| format!("failed to parse config file {}", name))
+}
+
+/// Refuse the configuration file from `source`, or the files it included, if others may access
+/// them, unless `insecure` is set.
+///
+/// With a `users_file`, only the files with users are checked, and configurations from stdin
+/// or a URL are not either. This runs once logging is set up, so that warnings are not lost.
+fn check_config_permissions(
+    source: &ConfigSource,
+    config_file: &ConfigFile,
+    insecure: bool,
+) -> anyhow::Result<()> {
+    let main = source.path().filter(|_| config_file.users_file.is_none());
+    for path in main
+        .into_iter()
+        .chain(config_file.included.iter().map(PathBuf::as_path))
+    {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        permissions::check_private(path, &metadata, insecure)?;
+    }
+    Ok(())
+}
+
+/// Configuration of the user `name`, falling back to the `"*"` entry for unknown users.
+fn find_user<'a>(users: &'a HashMap<String, UserConfig>, name: &str) -> Option<&'a UserConfig> {
+    users.get(name).or_else(|| users.get(WILDCARD_USER))
+}
+
+fn warn_insecure_users(users: &HashMap<String, UserConfig>) {
+    if users.contains_key(WILDCARD_USER) {
+        tracing::warn!(
+            "User {:?} is configured, any user name is accepted with its credentials",
+            WILDCARD_USER
+        );
+    }
+    for (name, user) in users {
+        if user.password.is_some() && user.password_hash.is_none() {
+            tracing::warn!(
+                "User {} has a plaintext password, consider using password_hash instead",
+                name
+            );
+        }
+    }
+}
+
+/// Load the configured host keys, logging their fingerprints.
+///
+/// Without configured keys, a key is generated only if `generate` is set. Keys others may
+/// access are refused unless `insecure` is set.
+fn load_host_keys(
+    config_file: &ConfigFile,
+    generate: bool,
+    insecure: bool,
+) -> anyhow::Result<Vec<russh_keys::key::KeyPair>> {
+    let host_key_paths: Vec<PathBuf> = config_file
+        .host_key
+        .iter()
+        .chain(config_file.host_keys.iter())
+        .cloned()
+        .collect();
+    let host_keys = if !host_key_paths.is_empty() {
+        host_key::load_all(&host_key_paths, insecure)?
+    } else if let Some(path) = &config_file.host_key_auto_path {
+        if path.exists() {
+            host_key::load_all(std::slice::from_ref(path), insecure)?
+        } else if generate {
+            tracing::info!("Generating new host key {}...", path.display());
+            vec![host_key::generate(
+                path,
+                false,
+                config_file.host_key_algorithm,
+            )?]
+        } else {
+            tracing::info!("Host key {} will be generated on start", path.display());
+            Vec::new()
+        }
+    } else if generate {
+        tracing::warn!(
+            "No host key configured, generating a temporary one that changes on every restart"
+        );
+        vec![config_file.host_key_algorithm.generate()?]
+    } else {
+        tracing::warn!("No host key configured, a temporary one will be generated on start");
+        Vec::new()
+    };
+    for key in &host_keys {
+        let public = key.clone_public_key()?;
+        tracing::info!("Host key {} SHA256:{}", public.name(), public.fingerprint());
+        tracing::debug!("Host key {} {}", public.name(), public.public_key_base64());
+    }
+    Ok(host_keys)
+}
+
+/// Install the global logger, which also receives the messages of the `russh` crates.
+fn init_logging(format: LogFormat, level: &str) -> anyhow::Result<()> {
+    let _ = LOG_SETTINGS.set((format, level.to_string()));
+    let level: LevelFilter = level
+        .parse()
+        .with_context(|| format!("invalid log_level {:?}", level))?;
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|e| anyhow::anyhow!("failed to initialize logging: {}", e))
+}
+
+/// Format and level of the log, for child processes logging on their own like the SFTP server.
+static LOG_SETTINGS: std::sync::OnceLock<(LogFormat, String)> = std::sync::OnceLock::new();
+
+/// Re-read the users from the configuration file of `source` whenever SIGHUP is received,
+/// checking its permissions like on start with `insecure`.
+///
+/// Only the users are reloaded, other settings require a restart. Existing sessions are not
+/// affected, and the previous users are kept if the file cannot be read. A configuration from
+/// stdin is not read again, but the files it includes are.
+fn reload_users_on_hangup(
+    source: ConfigSource,
+    insecure: bool,
+    users: Arc<ArcSwap<HashMap<String, UserConfig>>>,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context("failed to install SIGHUP handler")?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            systemd::reloading();
+            let config_file = read_config(&source).await.and_then(|config_file| {
+                check_config_permissions(&source, &config_file, insecure)?;
+                Ok(config_file)
+            });
+            match config_file {
+                Ok(config_file) => {
+                    warn_insecure_users(&config_file.users);
+                    tracing::info!("Reloaded {} users from {}", config_file.users.len(), source);
+                    users.store(Arc::new(config_file.users));
+                }
+                Err(e) => tracing::error!("Keeping the previous configuration: {:#}", e),
+            }
+            systemd::ready();
+        }
+    });
+    Ok(())
+}
+
+/// Notify `shutdown` when SIGTERM or SIGINT is received.
+fn notify_on_termination(shutdown: Arc<Notify>) -> anyhow::Result<()> {
+    let mut terminate =
+        signal(SignalKind::terminate()).context("failed to install SIGTERM handler")?;
+    let mut interrupt =
+        signal(SignalKind::interrupt()).context("failed to install SIGINT handler")?;
+    tokio::spawn(async move {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        };
+        tracing::info!("Received {}, shutting down...", name);
+        shutdown.notify_one();
+    });
+    Ok(())
+}
+
+/// Accept connections on all `listeners` until `shutdown` is notified.
+///
+/// This is `russh::server::run`, except that it can be stopped, serves several listeners and
+/// returns the sessions that are still running.
+async fn accept(
+    config: Arc<russh::server::Config>,
+    listeners: &[listener::Listener],
+    stream_options: &listener::StreamOptions,
+    proxy_protocol: bool,
+    mut server: Server,
+    shutdown: &Notify,
+) -> JoinSet<()> {
+    let mut sessions = JoinSet::new();
+    // Connections whose PROXY protocol header is still being read.
+    let mut proxied = JoinSet::new();
+    loop {
+        let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+        let (stream, peer_addr, local_addr) = tokio::select! {
+            (accepted, _, _) = futures::future::select_all(accepts) => match accepted {
+                Ok(connection) => {
+                    if let Err(e) = connection.configure(stream_options) {
+                        tracing::warn!("Failed to set socket options: {}", e);
+                    }
+                    let peer_addr = connection.peer_addr();
+                    let local_addr = connection.local_addr();
+                    let mut stream = connection.into_stream();
+                    if proxy_protocol {
+                        proxied.spawn(async move {
+                            let header = proxy_protocol::read_header(&mut stream).await;
+                            (stream, peer_addr, local_addr, header)
+                        });
+                        continue;
+                    }
+                    (stream, peer_addr, local_addr)
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(listener::ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            Some(result) = proxied.join_next() => match result {
+                Ok((stream, peer_addr, local_addr, Ok(client))) => {
+                    (stream, client.or(peer_addr), local_addr)
+                }
+                Ok((_, peer_addr, _, Err(e))) => {
+                    tracing::warn!(
+                        "Rejecting connection from {} without a valid PROXY protocol header: {}",
+                        describe_peer(peer_addr),
+                        e
+                    );
+                    continue;
+                }
+                Err(_) => continue,
+            },
+            Some(_) = sessions.join_next() => continue,
+            _ = shutdown.notified() => return sessions,
+        };
+        if let Some((reason, description)) = server.refusal(peer_addr).await {
+            refuse_connection(&config, stream, reason, description);
+            continue;
+        }
+        let permit = match &server.unauthenticated {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(Arc::new(permit)),
+                Err(_) => {
+                    tracing::warn!(
+                        "Refusing connection from {}: too many unauthenticated connections",
+                        describe_peer(peer_addr)
+                    );
+                    refuse_connection(
+                        &config,
+                        stream,
+                        Disconnect::TooManyConnections,
+                        &server.messages.too_many_connections,
+                    );
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let mut handler = server::Server::new_client(&mut server, peer_addr);
+        handler.unauthenticated_permit = permit;
+        handler.local_addr = local_addr;
+        let span = handler.span.clone();
+        let id = handler.id;
+        let shared = server.clone();
+        let config = config.clone();
+        let (stream, sever) = listener::Severable::new(stream);
+        server.connections.register(id, peer_addr, sever).await;
+        if let Some(grace) = server.login_grace_time {
+            let timer = server.expire_login(id, grace).instrument(span.clone());
+            handler.login_timer = Some(Arc::new(channel::AbortOnDrop(tokio::spawn(timer))));
+        }
+        let session = async move {
+            let result = match russh::server::run_stream(config, stream, handler).await {
+                Ok(session) => {
+                    shared.connections.started(id, session.handle()).await;
+                    session.await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::debug!("Session ended with an error: {:#}", e);
+            }
+            shared.connection_closed(id).await;
+        };
+        sessions.spawn(session.instrument(span));
+    }
+}
+
+/// Disconnect the client on `stream` in the background, telling it `description`.
+fn refuse_connection(
+    config: &russh::server::Config,
+    mut stream: Box<dyn listener::Stream>,
+    reason: Disconnect,
+    description: &str,
+) {
+    let id = identification(&config.server_id).to_string();
+    let description = description.to_string();
+    tokio::spawn(async move {
+        let _ = refuse::disconnect(&mut stream, &id, reason, &description).await;
+    });
+}
+
+/// The identification string sent to clients, without the line ending.
+fn identification(id: &SshId) -> &str {
+    match id {
+        SshId::Standard(id) => id,
+        SshId::Raw(id) => id.trim_end_matches(['\r', '\n']),
+    }
+}
+
+/// Format the address of a client for log messages.
+fn describe_peer(addr: Option<SocketAddr>) -> String {
+    addr.map_or_else(|| "unknown address".to_string(), |addr| addr.to_string())
+}
+
+/// `SSH_CLIENT` and `SSH_CONNECTION` of a connection from `peer` to `local`, set like OpenSSH
+/// does for connections over TCP.
+///
+/// IPv4 addresses mapped into IPv6, as seen on a dual-stack listener, are given as plain IPv4.
+fn connection_env(peer: Option<SocketAddr>, local: Option<SocketAddr>) -> Vec<(String, String)> {
+    let (Some(peer), Some(local)) = (peer, local) else {
+        return Vec::new();
+    };
+    let (peer_ip, local_ip) = (peer.ip().to_canonical(), local.ip().to_canonical());
+    vec![
+        (
+            "SSH_CLIENT".to_string(),
+            format!("{} {} {}", peer_ip, peer.port(), local.port()),
+        ),
+        (
+            "SSH_CONNECTION".to_string(),
+            format!("{} {} {} {}", peer_ip, peer.port(), local_ip, local.port()),
+        ),
+    ]
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Some(Command::HashPassword) => {
+            let mut line = String::new();
+            std::io::stdin()
+                .read_line(&mut line)
+                .context("failed to read password from stdin")?;
+            let password = line.trim_end_matches(['\r', '\n']);
+            println!("{}", password::hash(password)?);
+            return Ok(());
+        }
+        Some(Command::GenerateHostKey {
+            path,
+            overwrite,
+            algorithm,
+        }) => {
+            let key = host_key::generate(&path, overwrite, algorithm)?;
+            let public = key.clone_public_key()?;
+            println!("{} {}", public.name(), public.public_key_base64());
+            println!("SHA256:{}", public.fingerprint());
+            return Ok(());
+        }
+        Some(Command::SftpServer { settings }) => {
+            return sftp::serve_child(&settings).await;
+        }
+        Some(Command::CheckConfig) => {
+            let source = ConfigSource::from_arg(&args.config_file)?;
+            let config_file = read_config(&source).await?;
+            init_logging(config_file.log_format, &config_file.log_level)?;
+            check_config_permissions(&source, &config_file, args.insecure)?;
+            warn_insecure_users(&config_file.users);
+            load_host_keys(&config_file, false, args.insecure)?;
+            if let Some(banner) = &config_file.banner {
+                banner.load().context("failed to load banner")?;
+            }
+            println!("Configuration file {} is valid", source);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let source = ConfigSource::from_arg(&args.config_file)?;
+    let mut config_file = read_config(&source).await?;
+    init_logging(config_file.log_format, &config_file.log_level)?;
+    check_config_permissions(&source, &config_file, args.insecure)?;
+    warn_insecure_users(&config_file.users);
+    let host_keys = load_host_keys(&config_file, true, args.insecure)?;
+
+    let banner = match &config_file.banner {
+        // russh wants the banner for the lifetime of the server.
+        Some(banner) => Some(&*Box::leak(
+            banner
+                .load()
+                .context("failed to load banner")?
+                .into_boxed_str(),
+        )),
+        None => None,
+    };
+
+    let enabled_methods = config_file.auth_methods();
+    let preferred = config_file.algorithms().preferred()?;
+    let config = Arc::new(russh::server::Config {
+        connection_timeout: match config_file.connection_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        auth_rejection_time: Duration::from_secs(config_file.auth_rejection_time_secs),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        auth_banner: banner,
+        methods: enabled_methods,
+        preferred,
+        keys: host_keys,
+        window_size: config_file.window_size,
+        maximum_packet_size: config_file.maximum_packet_size,
+        server_id: match &config_file.server_id {
+            Some(server_id) => SshId::Standard(format!("SSH-2.0-{}", server_id)),
+            None => russh::server::Config::default().server_id,
+        },
+        ..Default::default()
+    });
+
+    let listen = if args.address.is_some() || args.port.is_some() {
+        vec![Listen {
+            address: args.address.unwrap_or_else(|| config_file.address.clone()),
+            port: args.port.unwrap_or(config_file.port),
+            unix: None,
+        }]
+    } else {
+        config_file.listeners()
+    };
+
+    let users = Arc::new(ArcSwap::from_pointee(std::mem::take(
+        &mut config_file.users,
+    )));
+    reload_users_on_hangup(source, args.insecure, users.clone())?;
+    let metrics = Arc::new(metrics::Metrics::new());
+    if let Some(addr) = config_file.metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to serve metrics on {}", addr))?;
+        tracing::info!("Serving metrics on {}...", addr);
+        tokio::spawn(metrics.clone().serve(listener));
+    }
+    let health = health::Health::default();
+    if let Some(addr) = config_file.health_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to serve health checks on {}", addr))?;
+        tracing::info!("Serving health checks on {}...", addr);
+        tokio::spawn(health.clone().serve(listener));
+    }
+    let events = match config_file.event_stream_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to stream events on {}", addr))?;
+            tracing::info!("Streaming events on {}...", addr);
+            let events = events::Events::new();
+            tokio::spawn(events.clone().serve(listener));
+            events
+        }
+        None => events::Events::default(),
+    };
+    let sh = Server::new(
+        &config_file,
+        users,
+        metrics,
+        events,
+        health.shutting_down.clone(),
+    );
+    if let Some(path) = &config_file.admin_socket {
+        let listener = listener::Listener::bind_unix(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("failed to restrict access to {}", path.display()))?;
+        tracing::info!("Serving admin commands on {}...", path.display());
+        tokio::spawn(admin::serve(
+            listener,
+            sh.connections.clone(),
+            sh.channels.clone(),
+            sh.messages.kicked.clone(),
+        ));
+    }
+
+    let mut listeners = listener::Listener::inherited()?;
+    let listen = if listeners.is_empty() {
+        listen
+    } else {
+        // Keep shells and commands from thinking they were socket-activated too.
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDNAMES");
+        for listener in &listeners {
+            tracing::info!("Listening on {} (passed by systemd)...", listener);
+        }
+        Vec::new()
+    };
+    let bind_options = listener::BindOptions {
+        backlog: config_file.tcp_backlog,
+        reuse_address: config_file.so_reuseaddr,
+        reuse_port: config_file.reuse_port,
+    };
+    for listen in listen {
+        let listener = match &listen.unix {
+            Some(path) => listener::Listener::bind_unix(path)?,
+            None => {
+                let addr = listen
+                    .socket_addr()
+                    .with_context(|| format!("invalid address {:?}", listen.address))?;
+                listener::Listener::bind_tcp(addr, &bind_options)?
+            }
+        };
+        tracing::info!("Listening on {}...", listener);
+        listeners.push(listener);
+    }
+
+    let shutdown = Arc::new(Notify::new());
+    notify_on_termination(shutdown.clone())?;
+    systemd::ready();
+    systemd::start_watchdog();
+    health.listening.store(true, Ordering::SeqCst);
+    let stream_options = listener::StreamOptions {
+        nodelay: config_file.tcp_nodelay,
+        keepalive: (config_file.keepalive_interval_secs > 0).then(|| {
+            let interval = Duration::from_secs(config_file.keepalive_interval_secs);
+            TcpKeepalive::new()
+                .with_time(interval)
+                .with_interval(interval)
+                .with_retries(config_file.keepalive_count_max)
+        }),
+    };
+    let sessions = accept(
+        config,
+        &listeners,
+        &stream_options,
+        config_file.proxy_protocol,
+        sh.clone(),
+        &shutdown,
+    )
+    .await;
+    systemd::stopping();
+    sh.shut_down(
+        Duration::from_secs(config_file.shutdown_grace_secs),
+        sessions,
+    )
+    .await;
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Server {
+    /// Open connections, for the admin socket.
+    connections: admin::Registry,
+    /// Handles of all open session channels, for telling them about a shutdown.
+    clients: Arc<Mutex<HashMap<(usize, ChannelId), russh::server::Handle>>>,
+    chat: chat::Chat,
+    channels: channel::Channels,
+    /// Configured users, replaced on SIGHUP.
+    users: Arc<ArcSwap<HashMap<String, UserConfig>>>,
+    denied_users: Arc<Vec<String>>,
+    allowed_users: Arc<Vec<String>>,
+    auth_failures: Arc<Mutex<HashMap<IpAddr, ban::FailureState>>>,
+    ban_policy: ban::Policy,
+    exec_wrapper: Arc<Vec<String>>,
+    motd: Arc<Option<message::Message>>,
+    login_script: Arc<Option<PathBuf>>,
+    auth_command: Arc<Option<PathBuf>>,
+    session_recording_dir: Arc<Option<PathBuf>>,
+    /// User whose `auth_command` answered `partial`, with the answers to its prompts so far.
+    auth_command_pending: Option<(String, Vec<String>)>,
+    /// Whether the message of the day was already shown on this connection.
+    motd_sent: bool,
+    max_auth_tries: u32,
+    /// Failed authentication attempts on this connection.
+    auth_tries: u32,
+    /// User whose password was correct, while `keyboard-interactive` asks for the verification
+    /// code.
+    totp_pending: Option<String>,
+    /// Whether the last verification code was wrong, to tell the client when it tries again.
+    totp_failed: bool,
+    /// Users of all open session channels, for limiting their number per user.
+    user_sessions: Arc<Mutex<HashMap<(usize, ChannelId), String>>>,
+    max_sessions: Option<usize>,
+    max_channels: Option<usize>,
+    /// Channels the client opened on this connection that are not closed yet.
+    open_channels: HashSet<ChannelId>,
+    /// Limits the number of connections that have not authenticated yet.
+    unauthenticated: Option<Arc<Semaphore>>,
+    max_connections: Option<usize>,
+    messages: Arc<Messages>,
+    /// Number of connections that have not ended yet.
+    open_connections: Arc<AtomicUsize>,
+    /// Held by this connection until it authenticates.
+    unauthenticated_permit: Option<Arc<OwnedSemaphorePermit>>,
+    login_grace_time: Option<Duration>,
+    /// Drops this connection once `login_grace_time` passed, until it authenticates.
+    login_timer: Option<Arc<channel::AbortOnDrop>>,
+    audit_log: audit::AuditLog,
+    events: events::Events,
+    mode: Mode,
+    accept_env: Arc<Vec<String>>,
+    allow_local_forward: bool,
+    local_forward_targets: Arc<Vec<String>>,
+    allow_remote_forward: bool,
+    remote_forward_ports: Arc<Vec<u16>>,
+    /// Ports listening for `ssh -R`, by connection, requested address and bound port.
+    remote_forwards: remote_forward::RemoteForwards,
+    allow_x11_forwarding: bool,
+    disallowed_key_algorithms: Arc<Vec<String>>,
+    log_keys: bool,
+    /// Authentication methods anyone may use.
+    enabled_methods: MethodSet,
+    idle_timeout: Option<Duration>,
+    idle_warning: Option<Duration>,
+    max_session_secs: u64,
+    /// Bytes per second each channel may send to the client, if limited.
+    bandwidth_limit: Option<u64>,
+    metrics: Arc<metrics::Metrics>,
+    /// Set once the server stops, telling sessions to disconnect when their channels close.
+    shutting_down: Arc<AtomicBool>,
+    /// Span of the connection, carrying its id, peer address and user.
+    span: tracing::Span,
+    id: usize,
+    peer_addr: Option<SocketAddr>,
+    /// Address of the server the client connected to.
+    local_addr: Option<SocketAddr>,
+    /// User whose credentials were last accepted, confirmed in `auth_succeeded`.
+    pending_user: Option<String>,
+    /// Options of the `authorized_keys` line of the key last accepted, if any.
+    pending_key_options: Option<authorized_keys::Options>,
+    /// Restrictions of the `authorized_keys` line the client authenticated with.
+    key_options: authorized_keys::Options,
+    /// User the client authenticated as, set once authentication has succeeded.
+    user: Option<String>,
+    /// Nicknames taken in the chat, by connection.
+    nicknames: Arc<Mutex<HashMap<usize, String>>>,
+    /// Name of this connection in the chat, derived from `user`.
+    nickname: Option<String>,
+}
+
+impl Server {
+    /// The server for `config_file`, with the `users` taken out of it.
+    fn new(
+        config_file: &ConfigFile,
+        users: Arc<ArcSwap<HashMap<String, UserConfig>>>,
+        metrics: Arc<metrics::Metrics>,
+        events: events::Events,
+        shutting_down: Arc<AtomicBool>,
+    ) -> Self {
+        Server {
+            connections: admin::Registry::default(),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            chat: chat::Chat::new(),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            users,
+            denied_users: Arc::new(config_file.denied_users.clone()),
+            allowed_users: Arc::new(config_file.allowed_users.clone()),
+            auth_failures: Arc::new(Mutex::new(HashMap::new())),
+            ban_policy: ban::Policy {
+                max_failures: config_file.max_auth_failures,
+                ban_duration: std::time::Duration::from_secs(config_file.ban_duration_secs),
+            },
+            exec_wrapper: Arc::new(config_file.exec_wrapper.clone()),
+            motd: Arc::new(config_file.motd.clone()),
+            login_script: Arc::new(config_file.login_script.clone()),
+            auth_command: Arc::new(config_file.auth_command.clone()),
+            session_recording_dir: Arc::new(config_file.session_recording_dir.clone()),
+            auth_command_pending: None,
+            motd_sent: false,
+            max_auth_tries: config_file.max_auth_tries,
+            auth_tries: 0,
+            totp_pending: None,
+            totp_failed: false,
+            user_sessions: Arc::new(Mutex::new(HashMap::new())),
+            max_sessions: config_file.max_sessions,
+            max_channels: config_file.max_channels_per_connection,
+            open_channels: HashSet::new(),
+            unauthenticated: config_file
+                .max_unauthenticated
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            unauthenticated_permit: None,
+            login_grace_time: (config_file.login_grace_time_secs > 0)
+                .then(|| Duration::from_secs(config_file.login_grace_time_secs)),
+            login_timer: None,
+            max_connections: config_file.max_connections,
+            messages: Arc::new(config_file.messages.clone()),
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            audit_log: config_file
+                .audit_log
+                .as_deref()
+                .map(audit::AuditLog::open)
+                .unwrap_or_default(),
+            events,
+            mode: config_file.mode,
+            accept_env: Arc::new(config_file.accept_env.clone()),
+            allow_local_forward: config_file.allow_local_forward,
+            local_forward_targets: Arc::new(config_file.local_forward_targets.clone()),
+            allow_remote_forward: config_file.allow_remote_forward,
+            remote_forward_ports: Arc::new(config_file.remote_forward_ports.clone()),
+            remote_forwards: Arc::new(Mutex::new(HashMap::new())),
+            allow_x11_forwarding: config_file.allow_x11_forwarding,
+            disallowed_key_algorithms: Arc::new(config_file.disallowed_key_algorithms()),
+            log_keys: config_file.log_keys,
+            enabled_methods: config_file.auth_methods(),
+            idle_timeout: (config_file.idle_timeout_secs > 0)
+                .then(|| Duration::from_secs(config_file.idle_timeout_secs)),
+            idle_warning: (config_file.idle_warning_secs > 0)
+                .then(|| Duration::from_secs(config_file.idle_warning_secs)),
+            max_session_secs: config_file.max_session_secs,
+            bandwidth_limit: (config_file.max_bandwidth_bytes_per_sec > 0)
+                .then_some(config_file.max_bandwidth_bytes_per_sec),
+            metrics,
+            shutting_down,
+            span: tracing::Span::none(),
+            id: 0,
+            peer_addr: None,
+            local_addr: None,
+            pending_user: None,
+            pending_key_options: None,
+            key_options: authorized_keys::Options::default(),
+            user: None,
+            nicknames: Arc::new(Mutex::new(HashMap::new())),
+            nickname: None,
+        }
+    }
+
+    /// Take a nickname for this connection in the chat, numbering it if `user` is already
+    /// chatting on another connection.
+    async fn register_nickname(&mut self, user: &str) {
+        let mut nicknames = self.nicknames.lock().await;
+        let nickname = (1..)
+            .map(|n| match n {
+                1 => user.to_string(),
+                n => format!("{}{}", user, n),
+            })
+            .find(|nickname| !nicknames.values().any(|taken| taken == nickname))
+            .expect("unbounded range");
+        nicknames.insert(self.id, nickname.clone());
+        self.nickname = Some(nickname);
+    }
+
+    /// Tell the chat that `nickname` joined or left it.
+    fn announce(&self, from: usize, nickname: &str, event: &str) {
+        tracing::info!("{} {} the chat", nickname, event);
+        let notice = format!("* {} {} the chat\r\n", nickname, event);
+        self.chat.post(from, CryptoVec::from(notice));
+    }
+
+    /// Close every channel, then wait up to `grace` for `sessions` to end.
+    ///
+    /// Interactive sessions are told why before their channel is closed, and clients are
+    /// disconnected once they confirm closing their channels.
+    async fn shut_down(&self, grace: Duration, mut sessions: JoinSet<()>) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let clients: Vec<_> = {
+            let clients = self.clients.lock().await;
+            let channels = self.channels.lock().await;
+            clients
+                .iter()
+                .map(|(key, handle)| {
+                    let interactive = matches!(
+                        channels.get(key).and_then(|state| state.process.as_ref()),
+                        Some(channel::Process::Pty(_))
+                    );
+                    (key.1, handle.clone(), interactive)
+                })
+                .collect()
+        };
+        let notice = format!("\r\n{}.\r\n", self.messages.shutdown.trim_end_matches('.'));
+        for (channel, handle, interactive) in clients {
+            if interactive {
+                let _ = handle
+                    .data(channel, CryptoVec::from_slice(notice.as_bytes()))
+                    .await;
+            }
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        }
+
+        let drained = tokio::time::timeout(grace, async {
+            while sessions.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                "Dropping {} sessions still open after {}s",
+                sessions.len(),
+                grace.as_secs()
+            );
+        }
+    }
+
+    /// Set up a newly opened channel, closing it once it is idle for longer than
+    /// `idle_timeout` or open for longer than the session limit of the user.
+    async fn init_channel(&self, channel: ChannelId, handle: russh::server::Handle) {
+        let mut channels = self.channels.lock().await;
+        let state = channels
+            .entry((self.id, channel))
+            .or_insert_with(|| channel::ChannelState {
+                traffic: self.traffic(),
+                ..Default::default()
+            });
+        if let Some(timeout) = self.idle_timeout {
+            state.idle = Some(idle::IdleTimer::start(
+                timeout,
+                self.idle_warning,
+                channel,
+                handle.clone(),
+            ));
+        }
+        if let Some(limit) = self.session_limit() {
+            state.lifetime = Some(lifetime::LifetimeTimer::start(limit, channel, handle));
+        }
+    }
+
+    /// Counters for a new channel, limited to `bandwidth_limit`.
+    fn traffic(&self) -> Arc<channel::Traffic> {
+        Arc::new(channel::Traffic::new(self.bandwidth_limit))
+    }
+
+    /// How long channels of the authenticated user may stay open, if limited.
+    fn session_limit(&self) -> Option<Duration> {
+        let users = self.users.load();
+        let secs = self
+            .user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .and_then(|userconfig| userconfig.max_session_secs)
+            .unwrap_or(self.max_session_secs);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// The client's address for log messages.
+    fn peer(&self) -> String {
+        describe_peer(self.peer_addr)
+    }
+
+    /// Check whether the client may open a connection to `target` with `ssh -L`.
+    fn local_forward_allowed(&self, target: &str) -> bool {
+        self.allow_local_forward
+            && !self.key_options.no_port_forwarding
+            && (self.local_forward_targets.is_empty()
+                || self.local_forward_targets.iter().any(|t| t == target))
+    }
+
+    /// Check whether the client may listen on `port` with `ssh -R`, 0 meaning any free port.
+    fn remote_forward_allowed(&self, port: u32) -> bool {
+        let Ok(port) = u16::try_from(port) else {
+            return false;
+        };
+        self.allow_remote_forward
+            && !self.key_options.no_port_forwarding
+            && if self.remote_forward_ports.is_empty() {
+                port == 0 || port >= MIN_UNPRIVILEGED_PORT
+            } else {
+                self.remote_forward_ports.contains(&port)
+            }
+    }
+
+    /// The command the authenticated user is restricted to, if any.
+    ///
+    /// Like OpenSSH's `ForceCommand`, `forced_command` takes precedence over the `command`
+    /// option of the key.
+    fn forced_command(&self) -> Option<String> {
+        let users = self.users.load();
+        self.user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .and_then(|userconfig| userconfig.forced_command.clone())
+            .or_else(|| self.key_options.command.clone())
+    }
+
+    /// Commands the authenticated user may run, or an empty list for any.
+    fn allowed_commands(&self) -> Vec<String> {
+        let users = self.users.load();
+        self.user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .map(|userconfig| userconfig.allowed_commands.clone())
+            .unwrap_or_default()
+    }
+
+    /// The `permit_open` list of the authenticated user, empty for any target.
+    fn permit_open(&self) -> Vec<String> {
+        let users = self.users.load();
+        self.user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .map(|userconfig| userconfig.permit_open.clone())
+            .unwrap_or_default()
+    }
+
+    /// The `permit_listen` list of the authenticated user, empty for any address.
+    fn permit_listen(&self) -> Vec<String> {
+        let users = self.users.load();
+        self.user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .map(|userconfig| userconfig.permit_listen.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether the authenticated user may only use SFTP.
+    fn sftp_only(&self) -> bool {
+        let users = self.users.load();
+        self.user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .is_some_and(|userconfig| userconfig.sftp_only)
+    }
+
+    /// Run `command` through the `exec_wrapper` on `channel`, on a pseudo-terminal if requested.
+    ///
+    /// `original` is the command requested by the client if `command` is a forced command.
+    async fn spawn_command(
+        &self,
+        channel: ChannelId,
+        command: &str,
+        original: Option<&str>,
+        handle: russh::server::Handle,
+    ) -> anyhow::Result<()> {
+        let mut channels = self.channels.lock().await;
+        let state = channels.entry((self.id, channel)).or_default();
+        let user_shell = self.user_shell();
+        let wrapper = match &user_shell {
+            Some(shell) => vec![shell.clone(), "-c".to_string()],
+            None => self.exec_wrapper.to_vec(),
+        };
+        let login = self.login()?;
+        let mut env = self.login_env(&login, &state.env);
+        env.push((
+            "SHELL".to_string(),
+            user_shell.unwrap_or_else(shell::default_shell),
+        ));
+        if let Some(original) = original {
+            env.push(("SSH_ORIGINAL_COMMAND".to_string(), original.to_string()));
+        }
+        let process = match &state.pty {
+            Some(pty) => {
+                let mut process = pty_process::Command::new(&wrapper[0]);
+                process.args(&wrapper[1..]).arg(command).envs(env);
+                login.configure_pty(&mut process);
+                channel::Process::Pty(shell::Shell::spawn(
+                    process,
+                    Some(pty),
+                    channel,
+                    handle,
+                    state.traffic.clone(),
+                    self.recorder(channel)?,
+                )?)
+            }
+            None => channel::Process::Pipes(exec::Exec::spawn(
+                &wrapper,
+                command,
+                &env,
+                &login,
+                channel,
+                handle,
+                state.traffic.clone(),
+            )),
+        };
+        state.process = Some(process);
+        Ok(())
+    }
+
+    /// Start recording the terminal session on `channel`, if `session_recording_dir` is set.
+    fn recorder(&self, channel: ChannelId) -> anyhow::Result<Option<recording::Recorder>> {
+        let Some(dir) = self.session_recording_dir.as_ref() else {
+            return Ok(None);
+        };
+        let user = self.user.as_deref().unwrap_or_default();
+        recording::Recorder::create(dir, user, self.id, channel).map(Some)
+    }
+
+    /// How to serve `root` over SFTP to the authenticated user, as the user and within its
+    /// `chroot_dir`, like its shells.
+    fn sftp_settings(&self, root: &Path) -> anyhow::Result<sftp::ChildSettings> {
+        let login = self.login()?;
+        let root = match &login.chroot {
+            Some(chroot) => {
+                let inside = root.strip_prefix(chroot).with_context(|| {
+                    format!(
+                        "{} is outside of chroot_dir {}",
+                        root.display(),
+                        chroot.display()
+                    )
+                })?;
+                Path::new("/").join(inside)
+            }
+            None => root.to_path_buf(),
+        };
+        let (log_format, log_level) = LOG_SETTINGS.get().cloned().unwrap_or_default();
+        Ok(sftp::ChildSettings {
+            root,
+            login,
+            connection: self.id,
+            log_format,
+            log_level,
+        })
+    }
+
+    /// Shell configured for the authenticated user, if any.
+    fn user_shell(&self) -> Option<String> {
+        let users = self.users.load();
+        self.user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .and_then(|userconfig| userconfig.shell.clone())
+    }
+
+    /// Working directory for processes of the authenticated user.
+    ///
+    /// Falls back to `/` if the home directory does not exist, which is looked up inside of
+    /// `chroot_dir` if the user has one.
+    fn home_dir(&self) -> Option<PathBuf> {
+        let name = self.user.as_ref()?;
+        let users = self.users.load();
+        let userconfig = find_user(&users, name)?;
+        let home = userconfig.home(name)?;
+        let on_host = match &userconfig.chroot_dir {
+            Some(chroot) => chroot.join(home.strip_prefix("/").unwrap_or(&home)),
+            None => home.clone(),
+        };
+        if on_host.is_dir() {
+            return Some(home);
+        }
+        tracing::warn!(
+            "Home directory {} of {} does not exist, using /",
+            on_host.display(),
+            name
+        );
+        Some(PathBuf::from("/"))
+    }
+
+    /// Where and as whom to run processes of the authenticated user.
+    ///
+    /// Fails if the server runs as root and the user has no ids to switch to, or if the
+    /// `chroot_dir` of the user is unusable.
+    fn login(&self) -> anyhow::Result<channel::Login> {
+        let name = self.user.as_deref().unwrap_or_default();
+        let users = self.users.load();
+        let userconfig = find_user(&users, name);
+        let mut login = channel::Login {
+            home: self.home_dir(),
+            ids: None,
+            chroot: userconfig.and_then(|userconfig| userconfig.chroot_dir.clone()),
+            rlimits: userconfig
+                .map(|userconfig| userconfig.rlimits)
+                .unwrap_or_default(),
+        };
+        let root = nix::unistd::geteuid().is_root();
+        if let Some(chroot) = &login.chroot {
+            if !root {
+                anyhow::bail!("chroot_dir of {} requires running the server as root", name);
+            }
+            channel::check_jail(chroot)
+                .with_context(|| format!("refusing to chroot {} to {}", name, chroot.display()))?;
+        }
+        if !root {
+            return Ok(login);
+        }
+        login.ids = userconfig.and_then(|u| u.ids(name));
+        if login.ids.is_none() {
+            anyhow::bail!(
+                "refusing to run processes of {} as root, configure uid and gid for it",
+                name
+            );
+        }
+        Ok(login)
+    }
+
+    /// `env` with the variables describing the authenticated user and the connection added.
+    fn login_env(&self, login: &channel::Login, env: &[(String, String)]) -> Vec<(String, String)> {
+        let mut env = env.to_vec();
+        if let Some(user) = &self.user {
+            env.push(("USER".to_string(), user.clone()));
+            env.push(("LOGNAME".to_string(), user.clone()));
+        }
+        if let Some(home) = &login.home {
+            env.push(("HOME".to_string(), home.display().to_string()));
+        }
+        env.extend(connection_env(self.peer_addr, self.local_addr));
+        env
+    }
+
+    /// Whether the client already has as many channels open as `max_channels_per_connection`
+    /// allows.
+    fn too_many_channels(&self) -> bool {
+        match self.max_channels {
+            Some(limit) if self.open_channels.len() >= limit => {
+                tracing::warn!(
+                    "Rejecting channel, the connection already has {} of {} allowed",
+                    self.open_channels.len(),
+                    limit
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Count a new session `channel` towards the limit of the authenticated user.
+    ///
+    /// Returns `false` if the user already has as many sessions as allowed.
+    async fn open_user_session(&self, channel: ChannelId) -> bool {
+        let user = match &self.user {
+            Some(user) => user,
+            None => return true,
+        };
+        let limit = find_user(&self.users.load(), user)
+            .and_then(|userconfig| userconfig.max_sessions)
+            .or(self.max_sessions);
+        let mut sessions = self.user_sessions.lock().await;
+        if let Some(limit) = limit {
+            let open = sessions.values().filter(|name| *name == user).count();
+            if open >= limit {
+                tracing::warn!(
+                    "Rejecting session of {}, who already has {} of {} allowed",
+                    user,
+                    open,
+                    limit
+                );
+                return false;
+            }
+        }
+        sessions.insert((self.id, channel), user.clone());
+        self.metrics.sessions.with_label_values(&[user]).inc();
+        true
+    }
+
+    /// Forget the channels, sessions and forwarded ports of connection `id` after it ended.
+    ///
+    /// Clients that drop the connection never close their channels, so whatever still runs on
+    /// them is stopped here.
+    async fn connection_closed(&self, id: usize) {
+        self.events
+            .send(events::Event::ConnectionClosed { connection: id });
+        self.connections.remove(id).await;
+        let chatting = {
+            let mut clients = self.clients.lock().await;
+            let open = clients.len();
+            clients.retain(|(connection, _), _| *connection != id);
+            clients.len() < open
+        };
+        let nickname = self.nicknames.lock().await.remove(&id);
+        if let Some(nickname) = nickname.filter(|_| chatting && self.mode == Mode::Chat) {
+            self.announce(id, &nickname, "left");
+        }
+        let orphaned: Vec<_> = {
+            let mut channels = self.channels.lock().await;
+            let keys: Vec<_> = channels
+                .keys()
+                .filter(|(connection, _)| *connection == id)
+                .copied()
+                .collect();
+            keys.iter().filter_map(|key| channels.remove(key)).collect()
+        };
+        if !orphaned.is_empty() {
+            tracing::debug!(
+                "Stopping {} channels left open by the client",
+                orphaned.len()
+            );
+        }
+        drop(orphaned);
+        self.remote_forwards
+            .lock()
+            .await
+            .retain(|(connection, _, _), _| *connection != id);
+        self.user_sessions
+            .lock()
+            .await
+            .retain(|(connection, _), user| {
+                if *connection != id {
+                    return true;
+                }
+                self.metrics.sessions.with_label_values(&[user]).dec();
+                false
+            });
+        self.metrics.connections.dec();
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// The message of the day for the authenticated user, unless it was already shown.
+    fn take_motd(&mut self) -> Option<String> {
+        if std::mem::replace(&mut self.motd_sent, true) {
+            return None;
+        }
+        let users = self.users.load();
+        let motd = self
+            .user
+            .as_ref()
+            .and_then(|name| find_user(&users, name))
+            .and_then(|userconfig| userconfig.motd.as_ref())
+            .or(self.motd.as_ref().as_ref())?;
+        match motd.load() {
+            Ok(motd) => Some(motd),
+            Err(e) => {
+                tracing::warn!("Failed to load message of the day: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Check whether `password` is the configured password of `user`.
+    ///
+    /// This takes about as long for unknown users and users without a password hash as for
+    /// users with one.
+    fn check_password(&self, user: &str, password: &str) -> bool {
+        match find_user(&self.users.load(), user) {
+            Some(UserConfig {
+                password_hash: Some(hash),
+                ..
+            }) => password::verify_hash(hash, password),
+            Some(UserConfig {
+                password: Some(expected),
+                ..
+            }) => {
+                password::verify_dummy(password);
+                password::verify_plain(expected, password)
+            }
+            _ => {
+                password::verify_dummy(password);
+                false
+            }
+        }
+    }
+
+    /// Whether `user` may log in at all according to `denied_users` and `allowed_users`.
+    fn user_permitted(&self, user: &str) -> bool {
+        !self
+            .denied_users
+            .iter()
+            .any(|pattern| glob_match(pattern, user))
+            && (self.allowed_users.is_empty()
+                || self
+                    .allowed_users
+                    .iter()
+                    .any(|pattern| glob_match(pattern, user)))
+    }
+
+    /// Ask the `auth_command` about an attempt of `user` with `method`, if one is configured.
+    ///
+    /// Users without an entry in `users` or not permitted to log in are rejected without
+    /// asking.
+    async fn ask_auth_command(
+        &self,
+        user: &str,
+        method: &str,
+        password: Option<&str>,
+        public_key: Option<&key::PublicKey>,
+        responses: &[String],
+    ) -> Option<auth_command::Verdict> {
+        let path = self.auth_command.as_ref().as_ref()?;
+        find_user(&self.users.load(), user)?;
+        if !self.user_permitted(user) {
+            return Some(auth_command::Verdict::Reject);
+        }
+        let attempt = auth_command::Attempt {
+            user,
+            method,
+            peer: self.peer_addr,
+            password,
+            public_key: public_key.map(|key| format!("{} {}", key.name(), key.public_key_base64())),
+            responses,
+        };
+        Some(auth_command::decide(path, &attempt).await)
+    }
+
+    /// Whether the `auth_command` accepts an attempt of a method without further prompts.
+    async fn auth_command_accepts(
+        &self,
+        user: &str,
+        method: &str,
+        password: Option<&str>,
+        public_key: Option<&key::PublicKey>,
+    ) -> bool {
+        match self
+            .ask_auth_command(user, method, password, public_key, &[])
+            .await
+        {
+            Some(auth_command::Verdict::Accept) => true,
+            Some(auth_command::Verdict::Partial { .. }) => {
+                tracing::warn!(
+                    "Rejecting {} for {}: the auth command answered partial, which only \
+                     keyboard-interactive supports",
+                    method,
+                    user
+                );
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Why a new connection from `peer_addr` must be refused, if it must.
+    async fn refusal(&self, peer_addr: Option<SocketAddr>) -> Option<(Disconnect, &str)> {
+        if let Some(addr) = peer_addr {
+            let mut failures = self.auth_failures.lock().await;
+            if self.ban_policy.is_banned(&mut failures, addr.ip()) {
+                tracing::warn!("Refusing connection from {}: the address is banned", addr);
+                return Some((Disconnect::HostNotAllowedToConnect, &self.messages.banned));
+            }
+        }
+        let limit = self.max_connections?;
+        if self.open_connections.load(Ordering::SeqCst) < limit {
+            return None;
+        }
+        tracing::warn!(
+            "Refusing connection from {}: the limit of {} connections is reached",
+            describe_peer(peer_addr),
+            limit
+        );
+        Some((
+            Disconnect::TooManyConnections,
+            &self.messages.too_many_connections,
+        ))
+    }
+
+    /// Check whether the client's address is banned after too many failed attempts.
+    async fn is_banned(&self) -> bool {
+        match self.peer_addr {
+            Some(addr) => {
+                let mut failures = self.auth_failures.lock().await;
+                self.ban_policy.is_banned(&mut failures, addr.ip())
+            }
+            None => false,
+        }
+    }
+
+    /// Turn the outcome of checking the credentials of `user` into an authentication result.
+    ///
+    /// Valid credentials are still rejected if the user may not log in from the client's address,
+    /// without hinting at the reason. Failures count towards banning the client's address.
+    async fn auth_result(
+        &mut self,
+        method: &str,
+        user: &str,
+        fingerprint: Option<String>,
+        accepted: bool,
+    ) -> server::Auth {
+        // The credentials were checked anyway, so rejecting takes as long as for wrong ones.
+        let permitted = self.user_permitted(user);
+        if accepted && !permitted {
+            tracing::warn!(
+                method,
+                user,
+                "Rejecting {} for {}, who is not allowed to log in",
+                method,
+                user
+            );
+        }
+        let accepted = accepted && permitted;
+        let source_allowed = find_user(&self.users.load(), user)
+            .is_some_and(|userconfig| userconfig.allows_source(self.peer_addr.map(|a| a.ip())));
+        if accepted && !source_allowed {
+            tracing::warn!(
+                method,
+                user,
+                "Rejected {} for {} from disallowed address {}",
+                method,
+                user,
+                self.peer()
+            );
+            self.audit(method, user, fingerprint, audit::Outcome::Reject);
+            self.record_failure().await;
+            self.too_many_tries().await;
+            return reject_banned();
+        }
+        if accepted {
+            self.audit(method, user, fingerprint, audit::Outcome::Accept);
+            self.pending_user = Some(user.to_string());
+            tracing::info!(
+                method,
+                user,
+                "Accepted {} for {} from {}",
+                method,
+                user,
+                self.peer()
+            );
+            return server::Auth::Accept;
+        }
+        tracing::info!(
+            method,
+            user,
+            "Rejected {} for {} from {}",
+            method,
+            user,
+            self.peer()
+        );
+        self.audit(method, user, fingerprint, audit::Outcome::Reject);
+        self.record_failure().await;
+        if self.too_many_tries().await {
+            return reject_banned();
+        }
+        reject(self.auth_methods(user))
+    }
+
+    /// Drop connection `id` once `grace` passed, unless this is cancelled first.
+    fn expire_login(&self, id: usize, grace: Duration) -> impl std::future::Future<Output = ()> {
+        let connections = self.connections.clone();
+        async move {
+            tokio::time::sleep(grace).await;
+            tracing::info!(
+                "Dropping connection that did not authenticate within {}s",
+                grace.as_secs()
+            );
+            connections.sever(id).await;
+        }
+    }
+
+    /// Count a failed attempt on this connection, dropping it once `max_auth_tries` is reached.
+    ///
+    /// Returns whether the connection is dropped. russh cannot disconnect from authentication
+    /// handlers, so the client is not told why.
+    async fn too_many_tries(&mut self) -> bool {
+        self.auth_tries += 1;
+        if self.max_auth_tries == 0 || self.auth_tries < self.max_auth_tries {
+            return false;
+        }
+        tracing::warn!(
+            "Dropping {} after {} failed authentication attempts",
+            self.peer(),
+            self.auth_tries
+        );
+        self.connections.sever(self.id).await;
+        true
+    }
+
+    /// Authentication methods `user` may use, or all supported ones for unknown users.
+    fn auth_methods(&self, user: &str) -> MethodSet {
+        find_user(&self.users.load(), user).map_or(SUPPORTED_METHODS, UserConfig::auth_methods)
+            & self.enabled_methods
+    }
+
+    /// Record an authentication attempt in the audit log.
+    fn audit(
+        &self,
+        method: &str,
+        user: &str,
+        fingerprint: Option<String>,
+        outcome: audit::Outcome,
+    ) {
+        self.metrics
+            .auth_attempts_total
+            .with_label_values(&[method, outcome.name()])
+            .inc();
+        self.events.send(events::Event::Auth {
+            connection: self.id,
+            user,
+            method,
+            outcome,
+        });
+        self.audit_log.record(&audit::AuthEvent {
+            peer: self.peer_addr.map(|addr| addr.ip()),
+            user,
+            method,
+            fingerprint,
+            outcome,
+        });
+    }
+
+    /// Log the public key `key` offered for `user` and whether it was accepted, if `log_keys`
+    /// is set.
+    fn log_key(&self, user: &str, key: &key::PublicKey, result: &server::Auth) {
+        if !self.log_keys {
+            return;
+        }
+        let outcome = match result {
+            server::Auth::Accept => "Accepted",
+            _ => "Rejected",
+        };
+        tracing::info!(
+            user,
+            "{} key {} {} for {}",
+            outcome,
+            key.name(),
+            key.public_key_base64(),
+            user
+        );
+    }
+
+    /// Count a failed authentication attempt towards banning the client's address.
+    async fn record_failure(&self) {
+        if let Some(addr) = self.peer_addr {
+            let mut failures = self.auth_failures.lock().await;
+            self.ban_policy.record_failure(&mut failures, addr.ip());
+        }
+    }
+}
+
+/// Check whether `destination` matches any of the `permit_open` or `permit_listen` entries
+/// `patterns`, an empty list allowing any.
+fn permitted(patterns: &[String], destination: &str) -> bool {
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, destination))
+}
+
+/// Check whether `name` matches `pattern`, where `*` matches any number of characters and `?`
+/// matches a single one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.split_first(), name.split_first()) {
+            (None, _) => name.is_empty(),
+            (Some((b'*', rest)), _) => {
+                matches(rest, name) || (!name.is_empty() && matches(pattern, &name[1..]))
+            }
+            (Some((b'?', rest)), Some((_, name))) => matches(rest, name),
+            (Some((p, rest)), Some((n, name))) if p == n => matches(rest, name),
+            _ => false,
+        }
+    }
+    matches(pattern.as_bytes(), name.as_bytes())
+}
+
+/// Ports below this require privileges to listen on, and must be allowed explicitly.
+const MIN_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// Exit status of shells and commands refused by the login script or the user's restrictions.
+const REFUSED_STATUS: u32 = 1;
+
+/// Shown to SFTP-only users asking for a shell or command.
+const SFTP_ONLY_MESSAGE: &str = "This account can only be used for SFTP.\n";
+
+/// Shown to users with `allowed_commands` asking for another command or a shell.
+const COMMAND_NOT_ALLOWED_MESSAGE: &str = "This command is not allowed for this account.\n";
+
+/// Shown to users asking for a command on a server in chat or echo mode.
+const NO_COMMANDS_MESSAGE: &str = "This server does not run commands.\n";
+
+/// Refuse the shell or command requested on `channel`, showing the client `message`.
+///
+/// Clients drop output received before the failure of a request, so this looks like a shell
+/// exiting right away instead.
+fn refuse_session(session: &mut Session, channel: ChannelId, message: &[u8]) {
+    let message = String::from_utf8_lossy(message).replace('\n', "\r\n");
+    session.channel_success(channel);
+    session.extended_data(
+        channel,
+        exec::EXTENDED_DATA_STDERR,
+        CryptoVec::from(message),
+    );
+    session.exit_status_request(channel, REFUSED_STATUS);
+    session.eof(channel);
+    session.close(channel);
+}
+
+/// Rejection sent to banned clients, without hinting at other methods.
+fn reject_banned() -> server::Auth {
+    server::Auth::Reject {
+        proceed_with_methods: None,
+    }
+}
+
+/// A `keyboard-interactive` round with a single, non-echoed `prompt`.
+fn ask(instructions: &'static str, prompt: impl Into<Cow<'static, str>>) -> server::Auth {
+    server::Auth::Partial {
+        name: Cow::Borrowed(""),
+        instructions: Cow::Borrowed(instructions),
+        prompts: Cow::Owned(vec![(prompt.into(), false)]),
+    }
+}
+
+/// Rejection letting the client try again with any of `methods`.
+fn reject(methods: MethodSet) -> server::Auth {
+    server::Auth::Reject {
+        proceed_with_methods: Some(methods),
+    }
+}
+
+impl server::Server for Server {
+    type Handler = Self;
+    fn new_client(&mut self, peer_addr: Option<SocketAddr>) -> Self {
+        let mut s = self.clone();
+        s.peer_addr = peer_addr;
+        s.span = tracing::info_span!(
+            "connection",
+            id = self.id,
+            peer = %s.peer(),
+            user = tracing::field::Empty
+        );
+        s.events.send(events::Event::ConnectionOpened {
+            connection: s.id,
+            peer: peer_addr,
+        });
+        self.id += 1;
+        self.metrics.connections_total.inc();
+        self.metrics.connections.inc();
+        self.open_connections.fetch_add(1, Ordering::SeqCst);
+        s
+    }
+}
+
+#[async_trait]
+impl server::Handler for Server {
+    type Error = anyhow::Error;
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn channel_open_session(
+        mut self,
+        channel: Channel<Msg>,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        if self.too_many_channels() || !self.open_user_session(channel.id()).await {
+            return Ok((self, false, session));
+        }
+        let joined = {
+            let mut clients = self.clients.lock().await;
+            let joined = !clients.keys().any(|(id, _)| *id == self.id);
+            clients.insert((self.id, channel.id()), session.handle());
+            joined
+        };
+        if let Some(nickname) = self.nickname.as_ref().filter(|_| joined) {
+            self.announce(self.id, nickname, "joined");
+        }
+        self.init_channel(channel.id(), session.handle()).await;
+        if self.mode == Mode::Chat {
+            let mut channels = self.channels.lock().await;
+            let state = channels.entry((self.id, channel.id())).or_default();
+            let chat = self.chat.join(
+                self.id,
+                channel.id(),
+                session.handle(),
+                state.traffic.clone(),
+            );
+            state.chat = Some(chat);
+        }
+        self.events.send(events::Event::ChannelOpened {
+            connection: self.id,
+            channel: channel.id(),
+            kind: "session",
+        });
+        self.open_channels.insert(channel.id());
+        Ok((self, true, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn auth_none(self, user: &str) -> Result<(Self, server::Auth), Self::Error> {
+        // Clients send this to learn which methods they may use. russh ignores
+        // `proceed_with_methods` here and offers `methods` of the config instead.
+        tracing::debug!("Client asked for authentication methods of {}", user);
+        let methods = self.auth_methods(user);
+        Ok((self, reject(methods)))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
     async fn auth_password(
-        self,
+        mut self,
         user: &str,
         password: &str,
     ) -> Result<(Self, server::Auth), Self::Error> {
+        if self.is_banned().await {
+            self.audit("password", user, None, audit::Outcome::Reject);
+            return Ok((self, reject_banned()));
+        }
+        // Check the password even if the method is not allowed, to take the same time either way.
+        let matches = self.check_password(user, password);
+        let allowed = self.auth_methods(user).contains(MethodSet::PASSWORD);
+        let accepted = allowed
+            && (matches
+                || self
+                    .auth_command_accepts(user, "password", Some(password), None)
+                    .await);
+        // russh ignores `proceed_with_methods` here and only stops offering `password`.
+        let result = self.auth_result("password", user, None, accepted).await;
+        Ok((self, result))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn auth_keyboard_interactive(
+        mut self,
+        user: &str,
+        _submethods: &str,
+        response: Option<Response<'async_trait>>,
+    ) -> Result<(Self, server::Auth), Self::Error> {
+        if self.is_banned().await {
+            self.audit("keyboard-interactive", user, None, audit::Outcome::Reject);
+            return Ok((self, reject_banned()));
+        }
+        if !self
+            .auth_methods(user)
+            .contains(MethodSet::KEYBOARD_INTERACTIVE)
+        {
+            let result = self
+                .auth_result("keyboard-interactive", user, None, false)
+                .await;
+            return Ok((self, result));
+        }
+        let mut response = match response {
+            Some(response) => response,
+            None => {
+                // First round: ask for the password with a single, non-echoed prompt.
+                self.totp_pending = None;
+                self.auth_command_pending = None;
+                let instructions = if std::mem::take(&mut self.totp_failed) {
+                    "The verification code was wrong."
+                } else {
+                    ""
+                };
+                return Ok((self, ask(instructions, "Password: ")));
+            }
+        };
+        let answer = response.next().map(std::str::from_utf8);
+        if self.totp_pending.take().as_deref() == Some(user) {
+            // Second round of users with a `totp_secret`: check the verification code.
+            let secret = find_user(&self.users.load(), user).and_then(|u| u.totp_secret.clone());
+            let accepted = matches!(
+                (answer, secret),
+                (Some(Ok(code)), Some(secret)) if secret.verify(code)
+            );
+            self.totp_failed = !accepted;
+            let result = self
+                .auth_result("keyboard-interactive", user, None, accepted)
+                .await;
+            return Ok((self, result));
+        }
+        if let Some((pending, mut responses)) = self.auth_command_pending.take() {
+            if pending == user {
+                // Later rounds: the auth command asked another question.
+                let mut accepted = false;
+                if let Some(Ok(answer)) = answer {
+                    responses.push(answer.to_string());
+                    let verdict = self
+                        .ask_auth_command(user, "keyboard-interactive", None, None, &responses)
+                        .await;
+                    match verdict {
+                        Some(auth_command::Verdict::Partial { prompt }) => {
+                            self.auth_command_pending = Some((pending, responses));
+                            return Ok((self, ask("", prompt)));
+                        }
+                        verdict => accepted = verdict == Some(auth_command::Verdict::Accept),
+                    }
+                }
+                let result = self
+                    .auth_result("keyboard-interactive", user, None, accepted)
+                    .await;
+                return Ok((self, result));
+            }
+        }
+        let mut accepted =
+            matches!(answer, Some(Ok(password)) if self.check_password(user, password));
+        if let (false, Some(Ok(password))) = (accepted, answer) {
+            let verdict = self
+                .ask_auth_command(user, "keyboard-interactive", Some(password), None, &[])
+                .await;
+            match verdict {
+                Some(auth_command::Verdict::Partial { prompt }) => {
+                    self.auth_command_pending = Some((user.to_string(), Vec::new()));
+                    return Ok((self, ask("", prompt)));
+                }
+                verdict => accepted = verdict == Some(auth_command::Verdict::Accept),
+            }
+        }
+        let needs_code =
+            find_user(&self.users.load(), user).is_some_and(|u| u.totp_secret.is_some());
+        if accepted && needs_code && self.user_permitted(user) {
+            self.totp_pending = Some(user.to_string());
+            return Ok((self, ask("", "Verification code: ")));
+        }
         let result = self
-            .users
-            .get(user)
-            .and_then(|userconfig| userconfig.password.as_ref())
-            .filter(|pw| pw == &password)
-            .map(|_| server::Auth::Accept)
-            .unwrap_or_else(|| server::Auth::Reject {
-                proceed_with_methods: Some(MethodSet::all()),
-            });
+            .auth_result("keyboard-interactive", user, None, accepted)
+            .await;
         Ok((self, result))
     }
 
+    #[tracing::instrument(parent = &self.span, skip_all)]
     async fn auth_publickey(
-        self,
+        mut self,
         user: &str,
         k: &key::PublicKey,
     ) -> Result<(Self, server::Auth), Self::Error> {
+        let fingerprint = format!("SHA256:{}", k.fingerprint());
+        if self.is_banned().await {
+            self.audit("publickey", user, Some(fingerprint), audit::Outcome::Reject);
+            return Ok((self, reject_banned()));
+        }
+        if self.disallowed_key_algorithms.iter().any(|a| a == k.name()) {
+            tracing::warn!(
+                "Refusing key {} for {}: the {} algorithm is disallowed",
+                fingerprint,
+                user,
+                k.name()
+            );
+            let result = self
+                .auth_result("publickey", user, Some(fingerprint), false)
+                .await;
+            self.log_key(user, k, &result);
+            return Ok((self, result));
+        }
+        let users = self.users.load_full();
+        let mut options = None;
+        let accepted = match find_user(&users, user) {
+            Some(userconfig) if !userconfig.auth_methods().contains(MethodSet::PUBLICKEY) => false,
+            Some(userconfig) if userconfig.keys.iter().any(|key| key.matches(k)) => true,
+            Some(UserConfig {
+                authorized_keys: Some(path),
+                ..
+            }) => {
+                options = authorized_keys::find(path, k, self.peer_addr.map(|a| a.ip())).await;
+                options.is_some()
+            }
+            _ => false,
+        };
+        self.pending_key_options = options;
+        let accepted = accepted
+            || (self.auth_methods(user).contains(MethodSet::PUBLICKEY)
+                && self
+                    .auth_command_accepts(user, "publickey", None, Some(k))
+                    .await);
         let result = self
-            .users
-            .get(user)
-            .filter(|userconfig| userconfig.keys.contains(&k.fingerprint()))
-            .map(|_| server::Auth::Accept)
-            .unwrap_or_else(|| server::Auth::Reject {
-                proceed_with_methods: Some(MethodSet::all()),
-            });
+            .auth_result("publickey", user, Some(fingerprint), accepted)
+            .await;
+        self.log_key(user, k, &result);
         Ok((self, result))
     }
 
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn auth_succeeded(mut self, session: Session) -> Result<(Self, Session), Self::Error> {
+        // An accepted public key may still fail the signature check, so only now is it certain
+        // which user the client is.
+        self.user = self.pending_user.take();
+        self.key_options = self.pending_key_options.take().unwrap_or_default();
+        self.unauthenticated_permit = None;
+        self.login_timer = None;
+        if let Some(user) = &self.user {
+            self.span.record("user", user.as_str());
+            tracing::info!("Authenticated as {}", user);
+            self.connections.authenticated(self.id, user).await;
+        }
+        if self.mode == Mode::Chat {
+            if let Some(user) = self.user.clone() {
+                self.register_nickname(&user).await;
+            }
+        }
+        if let Some(addr) = self.peer_addr {
+            let mut failures = self.auth_failures.lock().await;
+            self.ban_policy.record_success(&mut failures, addr.ip());
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn channel_close(
+        mut self,
+        channel: ChannelId,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        self.open_channels.remove(&channel);
+        if let Some(user) = self.user_sessions.lock().await.remove(&(self.id, channel)) {
+            self.metrics.sessions.with_label_values(&[&user]).dec();
+        }
+        let left = {
+            let mut clients = self.clients.lock().await;
+            clients.remove(&(self.id, channel)).is_some()
+                && !clients.keys().any(|(id, _)| *id == self.id)
+        };
+        if let Some(nickname) = self.nickname.as_ref().filter(|_| left) {
+            self.announce(self.id, nickname, "left");
+        }
+        // Dropping the state stops whatever runs on the channel.
+        let state = self.channels.lock().await.remove(&(self.id, channel));
+        if let Some(state) = &state {
+            let traffic = &state.traffic;
+            tracing::info!(
+                "Channel {:?} closed after {:.1?}, received {} bytes, sent {} bytes",
+                channel,
+                traffic.elapsed(),
+                traffic.received(),
+                traffic.sent()
+            );
+            self.metrics.sent_bytes_total.inc_by(traffic.sent());
+            self.events.send(events::Event::ChannelClosed {
+                connection: self.id,
+                channel,
+                received: traffic.received(),
+                sent: traffic.sent(),
+            });
+        }
+        if self.shutting_down.load(Ordering::SeqCst) {
+            session.disconnect(Disconnect::ByApplication, &self.messages.shutdown, "en");
+        } else if let Some(reason) = self.connections.kicked(self.id).await {
+            session.disconnect(Disconnect::ByApplication, &reason, "en");
+        } else if state
+            .as_ref()
+            .and_then(|state| state.idle.as_ref())
+            .is_some_and(|idle| idle.expired())
+        {
+            session.disconnect(Disconnect::ByApplication, &self.messages.idle_timeout, "en");
+        } else if state
+            .as_ref()
+            .and_then(|state| state.lifetime.as_ref())
+            .is_some_and(|lifetime| lifetime.expired())
+        {
+            session.disconnect(
+                Disconnect::ByApplication,
+                &self.messages.session_limit,
+                "en",
+            );
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn channel_eof(
+        self,
+        channel: ChannelId,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let process = {
+            let channels = self.channels.lock().await;
+            channels
+                .get(&(self.id, channel))
+                .and_then(|state| state.process.clone())
+        };
+        if let Some(process) = process {
+            if let Err(e) = process.eof().await {
+                tracing::debug!("Failed to pass on EOF on channel {:?}: {}", channel, e);
+            }
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn signal(
+        self,
+        channel: ChannelId,
+        signal: Sig,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let process = {
+            let channels = self.channels.lock().await;
+            channels
+                .get(&(self.id, channel))
+                .and_then(|state| state.process.clone())
+        };
+        match (process, channel::signal(&signal)) {
+            (None, _) => tracing::debug!(
+                "Ignoring signal {:?} for channel {:?} without a process",
+                signal,
+                channel
+            ),
+            (Some(_), None) => tracing::debug!("Ignoring unknown signal {:?}", signal),
+            (Some(process), Some(os_signal)) => {
+                tracing::debug!(
+                    "Sending {} to the process of channel {:?}",
+                    os_signal,
+                    channel
+                );
+                if let Err(e) = process.signal(os_signal) {
+                    tracing::warn!("Failed to send {}: {}", os_signal, e);
+                }
+            }
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    #[allow(clippy::too_many_arguments)]
+    async fn pty_request(
+        self,
+        channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+        modes: &[(Pty, u32)],
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if self.key_options.no_pty {
+            tracing::info!("Refusing pseudo-terminal for a key with no-pty");
+            session.channel_failure(channel);
+            return Ok((self, session));
+        }
+        {
+            let mut channels = self.channels.lock().await;
+            let state = channels.entry((self.id, channel)).or_default();
+            state.pty = Some(shell::PtyRequest::new(
+                term, col_width, row_height, pix_width, pix_height, modes,
+            ));
+            if let Some(idle) = &state.idle {
+                idle.interactive();
+            }
+        }
+        session.channel_success(channel);
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn env_request(
+        self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if !self
+            .accept_env
+            .iter()
+            .any(|pattern| glob_match(pattern, variable_name))
+        {
+            tracing::debug!("Ignoring environment variable {}", variable_name);
+            session.channel_failure(channel);
+            return Ok((self, session));
+        }
+        {
+            let mut channels = self.channels.lock().await;
+            let state = channels.entry((self.id, channel)).or_default();
+            state.env.retain(|(name, _)| name != variable_name);
+            state
+                .env
+                .push((variable_name.to_string(), variable_value.to_string()));
+        }
+        session.channel_success(channel);
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn x11_request(
+        self,
+        channel: ChannelId,
+        single_connection: bool,
+        x11_auth_protocol: &str,
+        x11_auth_cookie: &str,
+        x11_screen_number: u32,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if !self.allow_x11_forwarding || self.key_options.no_x11_forwarding {
+            tracing::debug!("Rejecting X11 forwarding on channel {:?}", channel);
+            session.channel_failure(channel);
+            return Ok((self, session));
+        }
+        let request = x11::Request {
+            single_connection,
+            auth_protocol: x11_auth_protocol,
+            auth_cookie: x11_auth_cookie,
+            screen_number: x11_screen_number,
+        };
+        let display = match self.login() {
+            Ok(login) => {
+                x11::Display::open(
+                    &request,
+                    login.ids,
+                    self.id,
+                    session.handle(),
+                    self.channels.clone(),
+                    self.bandwidth_limit,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match display {
+            Ok(forwarded) => {
+                tracing::info!(
+                    "Forwarding X11 display {} on channel {:?}",
+                    forwarded,
+                    channel
+                );
+                let mut channels = self.channels.lock().await;
+                let state = channels.entry((self.id, channel)).or_default();
+                state.env.extend(forwarded.env());
+                state.x11 = Some(forwarded);
+                session.channel_success(channel);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to forward X11: {:#}", e);
+                session.channel_failure(channel);
+            }
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn shell_request(
+        mut self,
+        channel: ChannelId,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if self.sftp_only() {
+            tracing::info!("Refusing shell for SFTP-only user {:?}", self.user);
+            refuse_session(&mut session, channel, SFTP_ONLY_MESSAGE.as_bytes());
+            return Ok((self, session));
+        }
+        if !self.allowed_commands().is_empty() {
+            tracing::info!(
+                "Refusing shell for {:?}, who may only run commands",
+                self.user
+            );
+            refuse_session(
+                &mut session,
+                channel,
+                COMMAND_NOT_ALLOWED_MESSAGE.as_bytes(),
+            );
+            return Ok((self, session));
+        }
+        if let Some(forced) = self.forced_command() {
+            match self
+                .spawn_command(channel, &forced, None, session.handle())
+                .await
+            {
+                Ok(()) => session.channel_success(channel),
+                Err(e) => {
+                    tracing::error!("Failed to run {:?}: {:#}", forced, e);
+                    session.channel_failure(channel);
+                }
+            }
+            return Ok((self, session));
+        }
+        if self.mode != Mode::Shell {
+            session.channel_success(channel);
+            return Ok((self, session));
+        }
+        if let Some(path) = self.login_script.as_ref() {
+            let mut env = vec![
+                (
+                    "SSH_USER".to_string(),
+                    self.user.clone().unwrap_or_default(),
+                ),
+                ("SSH_CHANNEL".to_string(), channel.to_string()),
+            ];
+            env.extend(connection_env(self.peer_addr, self.local_addr));
+            let refusal = match login_script::run(path, &env).await {
+                Ok(refusal) => refusal,
+                Err(e) => {
+                    tracing::error!("{:#}", e);
+                    Some(b"Login refused\n".to_vec())
+                }
+            };
+            if let Some(message) = refusal {
+                refuse_session(&mut session, channel, &message);
+                return Ok((self, session));
+            }
+        }
+        let spawned = {
+            let mut channels = self.channels.lock().await;
+            let state = channels.entry((self.id, channel)).or_default();
+            let program = self.user_shell().unwrap_or_else(shell::default_shell);
+            self.login().and_then(|login| {
+                let mut env = self.login_env(&login, &state.env);
+                env.push(("SHELL".to_string(), program.clone()));
+                let shell = shell::Shell::spawn_login(
+                    &program,
+                    &login,
+                    state.pty.as_ref(),
+                    &env,
+                    channel,
+                    session.handle(),
+                    state.traffic.clone(),
+                    self.recorder(channel)?,
+                )?;
+                state.process = Some(channel::Process::Pty(shell));
+                Ok(state.traffic.clone())
+            })
+        };
+        match spawned {
+            Ok(traffic) => {
+                session.channel_success(channel);
+                // Data sent here goes out before anything the shell writes through the handle.
+                if let Some(motd) = self.take_motd() {
+                    traffic.add_sent(motd.len());
+                    session.data(channel, CryptoVec::from(motd));
+                }
+            }
+            Err(e) => {
+                tracing::error!("{:#}", e);
+                session.channel_failure(channel);
+            }
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn exec_request(
+        self,
+        channel: ChannelId,
+        data: &[u8],
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let requested = String::from_utf8_lossy(data).into_owned();
+        if self.sftp_only() {
+            tracing::info!(
+                "Refusing command {:?} for SFTP-only user {:?}",
+                requested,
+                self.user
+            );
+            refuse_session(&mut session, channel, SFTP_ONLY_MESSAGE.as_bytes());
+            return Ok((self, session));
+        }
+        let allowed = self.allowed_commands();
+        if !allowed.is_empty() && !command_filter::allowed(&allowed, &requested) {
+            tracing::warn!(
+                "Refusing command {:?} not allowed for {:?}",
+                requested,
+                self.user
+            );
+            refuse_session(
+                &mut session,
+                channel,
+                COMMAND_NOT_ALLOWED_MESSAGE.as_bytes(),
+            );
+            return Ok((self, session));
+        }
+        let (command, original) = match self.forced_command() {
+            Some(forced) => (forced, Some(requested.as_str())),
+            None if self.mode != Mode::Shell => {
+                tracing::info!("Refusing command {:?} in {:?} mode", requested, self.mode);
+                refuse_session(&mut session, channel, NO_COMMANDS_MESSAGE.as_bytes());
+                return Ok((self, session));
+            }
+            None => (requested.clone(), None),
+        };
+        let spawned = self
+            .spawn_command(channel, &command, original, session.handle())
+            .await;
+        match spawned {
+            Ok(()) => session.channel_success(channel),
+            Err(e) => {
+                tracing::error!("Failed to run {:?}: {:#}", command, e);
+                session.channel_failure(channel);
+            }
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn subsystem_request(
+        self,
+        channel: ChannelId,
+        name: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if name != "sftp" {
+            tracing::debug!("Rejecting unknown subsystem {:?}", name);
+            session.channel_failure(channel);
+            return Ok((self, session));
+        }
+        if self.key_options.command.is_some() {
+            tracing::info!("Refusing SFTP for a key restricted to a command");
+            session.channel_failure(channel);
+            return Ok((self, session));
+        }
+        let root = self.user.as_ref().and_then(|name| {
+            find_user(&self.users.load(), name).and_then(|userconfig| userconfig.sftp_root(name))
+        });
+        let root = match root {
+            Some(root) => root,
+            None => {
+                tracing::warn!("No SFTP root directory for user {:?}", self.user);
+                session.channel_failure(channel);
+                return Ok((self, session));
+            }
+        };
+        let traffic = {
+            let mut channels = self.channels.lock().await;
+            channels
+                .entry((self.id, channel))
+                .or_default()
+                .traffic
+                .clone()
+        };
+        let spawned = self
+            .sftp_settings(&root)
+            .and_then(|settings| sftp::Sftp::spawn(&settings, channel, session.handle(), traffic));
+        match spawned {
+            Ok(sftp) => {
+                let mut channels = self.channels.lock().await;
+                channels.entry((self.id, channel)).or_default().process =
+                    Some(channel::Process::Sftp(sftp));
+                session.channel_success(channel);
+            }
+            Err(e) => {
+                tracing::error!("Failed to serve SFTP from {}: {:#}", root.display(), e);
+                session.channel_failure(channel);
+            }
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn window_change_request(
+        self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let size = shell::size(col_width, row_height, pix_width, pix_height);
+        let shell = {
+            let mut channels = self.channels.lock().await;
+            let state = channels.entry((self.id, channel)).or_default();
+            if let Some(pty) = &mut state.pty {
+                pty.size = size;
+            }
+            state.process.clone()
+        };
+        if let Some(channel::Process::Pty(shell)) = shell {
+            tracing::debug!(
+                "Resizing terminal of channel {:?} to {}x{}",
+                channel,
+                col_width,
+                row_height
+            );
+            if let Err(e) = shell.resize(size).await {
+                tracing::warn!("{:#}", e);
+            }
+        }
+        Ok((self, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
     async fn data(
         mut self,
         channel: ChannelId,
         data: &[u8],
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        let data = CryptoVec::from(format!("Got data: {}\r\n", String::from_utf8_lossy(data)));
-        self.post(data.clone()).await;
+        tracing::trace!(
+            "Received {} bytes on channel {:?} from {}",
+            data.len(),
+            channel,
+            self.peer()
+        );
+        self.metrics.received_bytes_total.inc_by(data.len() as u64);
+        let (process, traffic) = {
+            let channels = self.channels.lock().await;
+            let state = channels.get(&(self.id, channel));
+            if let Some(idle) = state.and_then(|state| state.idle.as_ref()) {
+                idle.touch();
+            }
+            (
+                state.and_then(|state| state.process.clone()),
+                state.map(|state| state.traffic.clone()),
+            )
+        };
+        if let Some(traffic) = &traffic {
+            traffic.add_received(data.len());
+        }
+        if let Some(process) = process {
+            if let Err(e) = process.write(data).await {
+                tracing::warn!("Failed to write to child process: {}", e);
+            }
+            return Ok((self, session));
+        }
+
+        if self.mode == Mode::Shell {
+            return Ok((self, session));
+        }
+        let data = match &self.nickname {
+            Some(nickname) if self.mode == Mode::Chat => {
+                let text = String::from_utf8_lossy(data);
+                let line = format!("<{}> {}\r\n", nickname, text.trim_end_matches(['\r', '\n']));
+                self.chat.post(self.id, CryptoVec::from(line.clone()));
+                CryptoVec::from(line)
+            }
+            _ => CryptoVec::from(format!("Got data: {}\r\n", String::from_utf8_lossy(data))),
+        };
+        if let Some(traffic) = &traffic {
+            traffic.pace_sent(data.len()).await;
+        }
         session.data(channel, data);
         Ok((self, session))
     }
 
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn channel_open_direct_tcpip(
+        mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        if self.too_many_channels() {
+            return Ok((self, false, session));
+        }
+        let target = format!("{}:{}", host_to_connect, port_to_connect);
+        let port = match u16::try_from(port_to_connect) {
+            Ok(port) if self.local_forward_allowed(&target) => port,
+            _ => {
+                tracing::warn!("Rejecting forwarding to {} for {:?}", target, self.user);
+                return Ok((self, false, session));
+            }
+        };
+        if !permitted(&self.permit_open(), &target) {
+            tracing::warn!(
+                "Rejecting forwarding to {} for {:?}, which is not in its permit_open",
+                target,
+                self.user
+            );
+            return Ok((self, false, session));
+        }
+        let traffic = self.traffic();
+        let forward = forward::Forward::connect(
+            host_to_connect,
+            port,
+            channel.id(),
+            session.handle(),
+            traffic.clone(),
+        )
+        .await;
+        match forward {
+            Ok(forward) => {
+                tracing::info!(
+                    "Forwarding {}:{} to {}",
+                    originator_address,
+                    originator_port,
+                    target
+                );
+                {
+                    let mut channels = self.channels.lock().await;
+                    let state = channels.entry((self.id, channel.id())).or_default();
+                    state.traffic = traffic;
+                    state.process = Some(channel::Process::Forward(forward));
+                }
+                self.init_channel(channel.id(), session.handle()).await;
+                self.events.send(events::Event::ChannelOpened {
+                    connection: self.id,
+                    channel: channel.id(),
+                    kind: "direct-tcpip",
+                });
+                self.open_channels.insert(channel.id());
+                Ok((self, true, session))
+            }
+            Err(e) => {
+                tracing::warn!("Failed to connect to {}: {}", target, e);
+                Ok((self, false, session))
+            }
+        }
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
     async fn tcpip_forward(
         self,
         address: &str,
         port: &mut u32,
         session: Session,
     ) -> Result<(Self, bool, Session), Self::Error> {
-        let handle = session.handle();
-        let address = address.to_string();
-        let port = *port;
+        if !self.remote_forward_allowed(*port) {
+            tracing::warn!("Refusing to listen on {}:{} for the client", address, port);
+            return Ok((self, false, session));
+        }
+        if !permitted(&self.permit_listen(), &format!("{}:{}", address, port)) {
+            tracing::warn!(
+                "Refusing to listen on {}:{} for {:?}, which is not in its permit_listen",
+                address,
+                port,
+                self.user
+            );
+            return Ok((self, false, session));
+        }
+        let requested = *port;
+        let listening = remote_forward::RemoteForward::listen(
+            address,
+            requested as u16,
+            self.id,
+            session.handle(),
+            self.channels.clone(),
+            self.bandwidth_limit,
+        )
+        .await;
+        let (forward, bound) = match listening {
+            Ok(listening) => listening,
+            Err(e) => {
+                tracing::warn!("Failed to listen on {}:{}: {}", address, requested, e);
+                return Ok((self, false, session));
+            }
+        };
+        tracing::info!("Listening on {}:{} for the client", address, bound);
+        *port = bound.into();
+        self.remote_forwards
+            .lock()
+            .await
+            .insert((self.id, address.to_string(), *port), forward);
+        Ok((self, true, session))
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
+    async fn cancel_tcpip_forward(
+        self,
+        address: &str,
+        port: u32,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        let cancelled = self
+            .remote_forwards
+            .lock()
+            .await
+            .remove(&(self.id, address.to_string(), port))
+            .is_some();
+        if cancelled {
+            tracing::info!("Stopped listening on {}:{} for the client", address, port);
+        } else {
+            tracing::warn!("Client cancelled unknown forward {}:{}", address, port);
+        }
+        Ok((self, cancelled, session))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use russh::server::Handler;
+
+    use super::*;
+
+    /// A server for the configuration file `contents`, without listening anywhere.
+    fn test_server(contents: &str) -> Server {
+        let mut config_file: ConfigFile = parse_file(contents, "test.toml").unwrap();
+        let users = Arc::new(ArcSwap::from_pointee(std::mem::take(
+            &mut config_file.users,
+        )));
+        Server::new(
+            &config_file,
+            users,
+            Arc::new(metrics::Metrics::new()),
+            events::Events::default(),
+            Arc::default(),
+        )
+    }
+
+    /// User `bob` with the plaintext password `secret`, quick to check.
+    const BOB: &str = "[users.bob]\npassword = \"secret\"\n";
+
+    struct Client;
+
+    #[async_trait]
+    impl client::Handler for Client {
+        type Error = russh::Error;
+
+        async fn check_server_key(self, _: &key::PublicKey) -> Result<(Self, bool), Self::Error> {
+            Ok((self, true))
+        }
+    }
+
+    /// Accept connections for `server` on a free port of the loopback interface.
+    fn listen(server: Server) -> SocketAddr {
+        let options = listener::BindOptions {
+            backlog: 16,
+            reuse_address: true,
+            reuse_port: false,
+        };
+        let listener =
+            listener::Listener::bind_tcp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = match &listener {
+            listener::Listener::Tcp(listener) => listener.local_addr().unwrap(),
+            listener::Listener::Unix(_) => unreachable!(),
+        };
+        let config = Arc::new(russh::server::Config {
+            keys: vec![key::KeyPair::generate_ed25519().unwrap()],
+            auth_rejection_time: Duration::ZERO,
+            methods: server.enabled_methods,
+            ..Default::default()
+        });
+        let options = listener::StreamOptions {
+            nodelay: true,
+            keepalive: None,
+        };
         tokio::spawn(async move {
-            let mut channel = handle
-                .channel_open_forwarded_tcpip(address, port, "1.2.3.4", 1234)
+            accept(config, &[listener], &options, false, server, &Notify::new()).await
+        });
+        addr
+    }
+
+    /// Connect to `addr` and authenticate as `bob`.
+    async fn connect(addr: SocketAddr) -> client::Handle<Client> {
+        let mut client = client::connect(Arc::default(), addr, Client).await.unwrap();
+        assert!(client.authenticate_password("bob", "secret").await.unwrap());
+        client
+    }
+
+    /// Read what a command run on `channel` writes to stdout until it finishes.
+    async fn output(channel: &mut russh::Channel<client::Msg>) -> String {
+        let mut output = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(message) = channel.wait().await {
+                if let ChannelMsg::Data { data } = message {
+                    output.extend_from_slice(&data);
+                }
+            }
+        });
+        read.await.expect("the command did not finish");
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    /// Wait until `condition` holds, failing after a few seconds.
+    async fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "{}", what);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn kicked_connections_are_closed() {
+        let server = test_server(BOB);
+        let mut client = connect(listen(server.clone())).await;
+        let mut channel = client.channel_open_session().await.unwrap();
+        assert!(
+            server
+                .connections
+                .kick(0, "maintenance", &server.channels)
+                .await
+        );
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            while channel.wait().await.is_some() {}
+        });
+        closed
+            .await
+            .expect("the channel of the kicked connection stayed open");
+        eventually("the kicked connection stayed open", || client.is_closed()).await;
+        let open = &server.open_connections;
+        eventually("the server kept the kicked connection", || {
+            open.load(Ordering::SeqCst) == 0
+        })
+        .await;
+        assert!(!server.connections.kick(0, "again", &server.channels).await);
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_leave_no_channels_behind() {
+        let server = test_server(BOB);
+        let addr = listen(server.clone());
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = connect(addr).await;
+            let channels = [
+                client.channel_open_session().await.unwrap(),
+                client.channel_open_session().await.unwrap(),
+            ];
+            clients.push((client, channels));
+        }
+        assert_eq!(server.clients.lock().await.len(), 6);
+        assert_eq!(server.channels.lock().await.len(), 6);
+
+        // Without closing the channels or disconnecting.
+        drop(clients);
+        let open = &server.open_connections;
+        eventually("the server kept the dropped connections", || {
+            open.load(Ordering::SeqCst) == 0
+        })
+        .await;
+        assert!(server.clients.lock().await.is_empty());
+        assert!(server.channels.lock().await.is_empty());
+        assert!(server.user_sessions.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requested_term_reaches_the_command() {
+        let ids = format!(
+            "uid = {}\ngid = {}\n",
+            nix::unistd::getuid(),
+            nix::unistd::getgid()
+        );
+        let server = test_server(&(BOB.to_string() + &ids));
+        let mut client = connect(listen(server)).await;
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel
+            .request_pty(false, "xterm-test", 80, 24, 0, 0, &[])
+            .await
+            .unwrap();
+        channel.exec(false, "echo \"$TERM\"").await.unwrap();
+        assert_eq!(output(&mut channel).await.trim(), "xterm-test");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs root"]
+    async fn commands_run_as_the_user() {
+        let server = test_server(&(BOB.to_string() + "uid = 65534\ngid = 65534\n"));
+        let mut client = connect(listen(server)).await;
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(false, "id -u; id -g").await.unwrap();
+        assert_eq!(output(&mut channel).await.trim(), "65534\n65534");
+    }
+
+    #[tokio::test]
+    async fn commands_are_refused_outside_shell_mode() {
+        let server = test_server(&format!("mode = \"echo\"\n{}", BOB));
+        let mut client = connect(listen(server)).await;
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel.exec(true, "echo ran").await.unwrap();
+        let mut status = None;
+        let read = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(message) = channel.wait().await {
+                match message {
+                    ChannelMsg::Data { data } => panic!("the command ran: {:?}", data),
+                    ChannelMsg::ExitStatus { exit_status } => status = Some(exit_status),
+                    _ => {}
+                }
+            }
+        });
+        read.await.expect("the channel was not closed");
+        assert_eq!(status, Some(REFUSED_STATUS));
+    }
+
+    #[tokio::test]
+    async fn commands_see_the_connection() {
+        let ids = format!(
+            "uid = {}\ngid = {}\n",
+            nix::unistd::getuid(),
+            nix::unistd::getgid()
+        );
+        let addr = listen(test_server(&(BOB.to_string() + &ids)));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let local = stream.local_addr().unwrap();
+        let mut client = client::connect_stream(Arc::default(), stream, Client)
+            .await
+            .unwrap();
+        assert!(client.authenticate_password("bob", "secret").await.unwrap());
+        let mut channel = client.channel_open_session().await.unwrap();
+        channel
+            .exec(false, "echo \"$SSH_CLIENT|$SSH_CONNECTION\"")
+            .await
+            .unwrap();
+        let expected = format!(
+            "{} {} {}|{} {} {} {}",
+            local.ip(),
+            local.port(),
+            addr.port(),
+            local.ip(),
+            local.port(),
+            addr.ip(),
+            addr.port()
+        );
+        assert_eq!(output(&mut channel).await.trim(), expected);
+    }
+
+    #[test]
+    fn connection_env_unmaps_ipv4_addresses() {
+        let env = connection_env(
+            Some("[::ffff:192.0.2.1]:50000".parse().unwrap()),
+            Some("[::ffff:192.0.2.2]:22".parse().unwrap()),
+        );
+        assert_eq!(
+            env,
+            [
+                ("SSH_CLIENT".to_string(), "192.0.2.1 50000 22".to_string()),
+                (
+                    "SSH_CONNECTION".to_string(),
+                    "192.0.2.1 50000 192.0.2.2 22".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_client_records_the_peer() {
+        let mut server = test_server(BOB);
+        let peer = "192.0.2.1:50000".parse().unwrap();
+        let client = server::Server::new_client(&mut server, Some(peer));
+        assert_eq!(client.peer_addr, Some(peer));
+        assert_eq!(client.peer(), "192.0.2.1:50000");
+        let unknown = server::Server::new_client(&mut server, None);
+        assert_eq!(unknown.peer_addr, None);
+        assert_eq!(unknown.peer(), "unknown address");
+        assert_ne!(client.id, unknown.id);
+    }
+
+    /// A connection that stops reading once `stalled` is set, like a client that went away
+    /// without closing it.
+    struct Stalling {
+        stream: tokio::net::TcpStream,
+        stalled: Arc<AtomicBool>,
+    }
+
+    impl tokio::io::AsyncRead for Stalling {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.stalled.load(Ordering::SeqCst) {
+                return std::task::Poll::Pending;
+            }
+            std::pin::Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl tokio::io::AsyncWrite for Stalling {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::pin::Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    /// Posters and readers chatting at once, two of the readers having stopped reading.
+    ///
+    /// Before posts went through a broadcast channel, the stalled readers held up everyone
+    /// else, and no line arrived within the minute.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture chat_throughput`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn chat_throughput_with_stalled_readers() {
+        const READERS: usize = 10;
+        const STALLED: usize = 2;
+        const POSTERS: usize = 4;
+        const LINES: usize = 3000;
+        let server = test_server(&format!("mode = \"chat\"\n{}", BOB));
+        let addr = listen(server);
+
+        let mut stalled = Vec::new();
+        for _ in 0..STALLED {
+            let flag = Arc::new(AtomicBool::new(false));
+            let stream = Stalling {
+                stream: tokio::net::TcpStream::connect(addr).await.unwrap(),
+                stalled: flag.clone(),
+            };
+            let mut client = client::connect_stream(Arc::default(), stream, Client)
                 .await
                 .unwrap();
-            let _ = channel.data(&b"Hello from a forwarded port"[..]).await;
-            let _ = channel.eof().await;
+            assert!(client.authenticate_password("bob", "secret").await.unwrap());
+            let channel = client.channel_open_session().await.unwrap();
+            flag.store(true, Ordering::SeqCst);
+            stalled.push((client, channel));
+        }
+        let mut readers = JoinSet::new();
+        let mut progress = Vec::new();
+        for _ in 0..READERS - STALLED {
+            let mut client = connect(addr).await;
+            let mut channel = client.channel_open_session().await.unwrap();
+            let counted = Arc::new(AtomicUsize::new(0));
+            progress.push(counted.clone());
+            readers.spawn(async move {
+                let mut received = Vec::new();
+                let mut lines = 0;
+                while lines < POSTERS * LINES {
+                    counted.store(lines, Ordering::Relaxed);
+                    match channel.wait().await {
+                        Some(ChannelMsg::Data { data }) => received.extend_from_slice(&data),
+                        Some(_) => continue,
+                        None => break,
+                    }
+                    while let Some(end) = received.windows(2).position(|w| w == b"\r\n") {
+                        let line = &received[..end];
+                        lines += usize::from(line.windows(6).any(|w| w == b"> post"));
+                        received.drain(..end + 2);
+                    }
+                }
+                drop(client);
+                lines
+            });
+        }
+
+        let started = Instant::now();
+        let mut posters = Vec::new();
+        for poster in 0..POSTERS {
+            let mut client = connect(addr).await;
+            let mut channel = client.channel_open_session().await.unwrap();
+            posters.push(tokio::spawn(async move {
+                let line = format!("post {}\n", poster);
+                let echo = format!("> post {}\r\n", poster);
+                for _ in 0..LINES {
+                    channel.data(line.as_bytes()).await.unwrap();
+                    // Like someone typing, wait for the line to show up before the next one.
+                    loop {
+                        match channel.wait().await {
+                            Some(ChannelMsg::Data { data }) if data.ends_with(echo.as_bytes()) => {
+                                break
+                            }
+                            Some(_) => continue,
+                            None => panic!("the chat closed the channel of a poster"),
+                        }
+                    }
+                }
+                (client, channel)
+            }));
+        }
+        let delivered = tokio::time::timeout(Duration::from_secs(60), async {
+            let mut delivered = Vec::new();
+            while let Some(lines) = readers.join_next().await {
+                delivered.push(lines.unwrap());
+            }
+            delivered
+        })
+        .await
+        .unwrap_or_else(|_| {
+            let counts: Vec<_> = progress.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+            panic!("not all lines arrived within a minute: {:?}", counts)
         });
-        Ok((self, true, session))
+        println!(
+            "{} readers received {} lines each in {:?}",
+            delivered.len(),
+            POSTERS * LINES,
+            started.elapsed()
+        );
+        assert!(delivered.iter().all(|&lines| lines == POSTERS * LINES));
+    }
+
+    /// Users `alice` with a password hash and `bob` with a plaintext password.
+    fn users() -> String {
+        format!(
+            "[users.alice]\npassword_hash = {:?}\n[users.bob]\npassword = \"secret\"\n",
+            password::hash("secret").unwrap()
+        )
+    }
+
+    fn socket_addr(address: &str) -> Option<SocketAddr> {
+        let listen = Listen {
+            address: address.to_string(),
+            port: 2222,
+            unix: None,
+        };
+        listen.socket_addr().ok()
+    }
+
+    #[test]
+    fn listens_on_ipv4_and_ipv6_addresses() {
+        let parsed = |addr: &str| Some(addr.parse::<SocketAddr>().unwrap());
+        assert_eq!(socket_addr("127.0.0.1"), parsed("127.0.0.1:2222"));
+        assert_eq!(socket_addr("0.0.0.0"), parsed("0.0.0.0:2222"));
+        assert_eq!(socket_addr("::1"), parsed("[::1]:2222"));
+        assert_eq!(socket_addr("[::1]"), parsed("[::1]:2222"));
+        assert_eq!(socket_addr("::"), parsed("[::]:2222"));
+        assert_eq!(socket_addr("[::]"), parsed("[::]:2222"));
+        assert_eq!(socket_addr("fe80::1"), parsed("[fe80::1]:2222"));
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for address in [
+            "",
+            "localhost",
+            "[127.0.0.1",
+            "::1]",
+            "127.0.0.1:22",
+            "[::1]:22",
+        ] {
+            assert_eq!(socket_addr(address), None, "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn reuse_port_has_two_names() {
+        for key in ["reuse_port", "so_reuseport"] {
+            let config_file: ConfigFile =
+                parse_file(&format!("{} = true\n", key), "test.toml").unwrap();
+            assert!(config_file.reuse_port, "{}", key);
+        }
+    }
+
+    #[test]
+    fn unknown_users_take_as_long_as_known_ones() {
+        let server = test_server(&users());
+        let time = |user: &str| {
+            let started = Instant::now();
+            assert!(!server.check_password(user, "wrong"));
+            started.elapsed()
+        };
+        let known = time("alice");
+        for user in ["bob", "mallory"] {
+            let elapsed = time(user);
+            assert!(
+                elapsed > known / 2,
+                "checking {} took {:?}, but {:?} for a password hash",
+                user,
+                elapsed,
+                known
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_users_are_rejected_like_known_ones() {
+        let server = test_server(&users());
+        let methods = |auth| match auth {
+            server::Auth::Reject {
+                proceed_with_methods,
+            } => proceed_with_methods,
+            _ => panic!("wrong password was not rejected"),
+        };
+        let (_, known) = server
+            .clone()
+            .auth_password("alice", "wrong")
+            .await
+            .unwrap();
+        let (_, unknown) = server.auth_password("mallory", "wrong").await.unwrap();
+        let known = methods(known);
+        assert!(known.is_some());
+        assert_eq!(methods(unknown), known);
     }
 }
@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Text shown to clients, given inline or read from a file.
+///
+/// In the config file, this is either a string or a table like `{ file = "/etc/issue.net" }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Message {
+    Inline(String),
+    File { file: PathBuf },
+}
+
+impl Message {
+    /// Load the text with the CRLF line endings expected by SSH clients.
+    pub fn load(&self) -> anyhow::Result<String> {
+        let text = match self {
+            Message::Inline(text) => text.clone(),
+            Message::File { file } => std::fs::read_to_string(file)
+                .with_context(|| format!("failed to read {}", file.display()))?,
+        };
+        Ok(crlf(&text))
+    }
+}
+
+/// Convert all line endings to CRLF.
+pub fn crlf(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\n', "\r\n")
+}
@@ -0,0 +1,133 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus::{
+    Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::net::TcpListener;
+
+/// Counters and gauges describing the server, exported in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    /// Connections accepted since the server started.
+    pub connections_total: IntCounter,
+    /// Currently open connections.
+    pub connections: IntGauge,
+    /// Currently open session channels, by user.
+    pub sessions: IntGaugeVec,
+    /// Authentication attempts, by method and outcome.
+    pub auth_attempts_total: IntCounterVec,
+    /// Channel data received from clients.
+    pub received_bytes_total: IntCounter,
+    /// Channel data sent to clients, counted once the channel is closed.
+    pub sent_bytes_total: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let connections_total =
+            IntCounter::new("ssh_connections_total", "Connections accepted").unwrap();
+        let connections = IntGauge::new("ssh_connections", "Currently open connections").unwrap();
+        let sessions = IntGaugeVec::new(
+            Opts::new("ssh_sessions", "Currently open session channels"),
+            &["user"],
+        )
+        .unwrap();
+        let auth_attempts_total = IntCounterVec::new(
+            Opts::new("ssh_auth_attempts_total", "Authentication attempts"),
+            &["method", "outcome"],
+        )
+        .unwrap();
+        let received_bytes_total = IntCounter::new(
+            "ssh_received_bytes_total",
+            "Channel data received from clients",
+        )
+        .unwrap();
+        let sent_bytes_total = IntCounter::new(
+            "ssh_sent_bytes_total",
+            "Channel data sent to clients on closed channels",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(connections_total.clone()))
+            .unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        registry.register(Box::new(sessions.clone())).unwrap();
+        registry
+            .register(Box::new(auth_attempts_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(received_bytes_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(sent_bytes_total.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            connections_total,
+            connections,
+            sessions,
+            auth_attempts_total,
+            received_bytes_total,
+            sent_bytes_total,
+        }
+    }
+
+    /// Answer HTTP requests for `/metrics` on `listener`.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept metrics connection: {}", e);
+                    tokio::time::sleep(crate::listener::ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(|request| {
+                    let response = metrics.respond(&request);
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    tracing::debug!("Metrics connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn respond(&self, request: &Request<Incoming>) -> Response<Full<Bytes>> {
+        let mut response = Response::new(Full::default());
+        if request.uri().path() != "/metrics" {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buffer) {
+            tracing::warn!("Failed to encode metrics: {}", e);
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return response;
+        }
+        *response.body_mut() = Full::new(Bytes::from(buffer));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            encoder.format_type().parse().expect("valid content type"),
+        );
+        response
+    }
+}
@@ -0,0 +1,61 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use subtle::ConstantTimeEq;
+
+/// Hash of a random password made with the same parameters as [`hash`].
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$FQ/aOMK6PnGYK9qz7rkZLQ$N0//sSpK8iJHDnQM3Ts+v3SW2E050hvlYhriOOmHkjk";
+
+/// Hash `password` into an argon2 PHC string suitable for `password_hash`.
+pub fn hash(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Check `password` against an argon2 PHC string.
+///
+/// A malformed hash never matches.
+pub fn verify_hash(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            tracing::warn!("Ignoring malformed password hash: {}", e);
+            false
+        }
+    }
+}
+
+/// Compare a plaintext password in constant time.
+pub fn verify_plain(expected: &str, password: &str) -> bool {
+    expected.as_bytes().ct_eq(password.as_bytes()).into()
+}
+
+/// Take as long as [`verify_hash`] without matching anything.
+///
+/// Used for users without a password hash, so that how long a rejection takes does not tell
+/// whether a user exists.
+pub fn verify_dummy(password: &str) {
+    let _ = verify_hash(DUMMY_HASH, password);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_real_ones() {
+        let real = hash("secret").unwrap();
+        let real = PasswordHash::new(&real).unwrap();
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+        assert!(!verify_hash(DUMMY_HASH, "secret"));
+    }
+}
@@ -0,0 +1,28 @@
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Permission bits letting group members or others read or change a file.
+const GROUP_OTHER: u32 = 0o077;
+
+/// Refuse the file at `path` if others than its owner may access it, since it contains
+/// secrets. With `insecure`, only warn instead.
+pub fn check_private(path: &Path, metadata: &Metadata, insecure: bool) -> anyhow::Result<()> {
+    let mode = metadata.mode() & 0o777;
+    if mode & GROUP_OTHER == 0 {
+        return Ok(());
+    }
+    if insecure {
+        tracing::warn!(
+            "{} is accessible by others (mode {:03o}), restrict it with chmod 600",
+            path.display(),
+            mode
+        );
+        return Ok(());
+    }
+    anyhow::bail!(
+        "{} is accessible by others (mode {:03o}), restrict it with chmod 600 or pass --insecure",
+        path.display(),
+        mode
+    )
+}
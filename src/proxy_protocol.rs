@@ -0,0 +1,70 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ppp::{v1, v2};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Length of the version 2 signature, shorter than any version 1 header.
+const V2_SIGNATURE_LENGTH: usize = 12;
+
+/// Length of the fixed part of a version 2 header, ending with the length of the rest.
+const V2_FIXED_LENGTH: usize = 16;
+
+/// Longest possible version 1 header, including the line ending.
+const V1_MAX_LENGTH: usize = 107;
+
+/// How long a proxy may take to send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read a PROXY protocol header of version 1 or 2 from `stream`, returning the client address.
+///
+/// Nothing after the header is read. `None` means the header names no client, e.g. for health
+/// checks of the proxy itself.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    tokio::time::timeout(HEADER_TIMEOUT, read(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))?
+}
+
+async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut buffer = vec![0; V2_SIGNATURE_LENGTH];
+    stream.read_exact(&mut buffer).await?;
+
+    if buffer == v2::PROTOCOL_PREFIX {
+        buffer.resize(V2_FIXED_LENGTH, 0);
+        stream
+            .read_exact(&mut buffer[V2_SIGNATURE_LENGTH..])
+            .await?;
+        let length = u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+        buffer.resize(V2_FIXED_LENGTH + length, 0);
+        stream.read_exact(&mut buffer[V2_FIXED_LENGTH..]).await?;
+        let header = v2::Header::try_from(&buffer[..]).map_err(invalid)?;
+        return Ok(match (header.command, header.addresses) {
+            (v2::Command::Local, _) => None,
+            (_, v2::Addresses::IPv4(a)) => Some((a.source_address, a.source_port).into()),
+            (_, v2::Addresses::IPv6(a)) => Some((a.source_address, a.source_port).into()),
+            _ => None,
+        });
+    }
+
+    if !buffer.starts_with(b"PROXY ") {
+        return Err(invalid("no PROXY protocol header"));
+    }
+    while !buffer.ends_with(b"\r\n") {
+        if buffer.len() >= V1_MAX_LENGTH {
+            return Err(invalid("header too long"));
+        }
+        buffer.push(stream.read_u8().await?);
+    }
+    let header = v1::Header::try_from(&buffer[..]).map_err(invalid)?;
+    Ok(match header.addresses {
+        v1::Addresses::Tcp4(a) => Some((a.source_address, a.source_port).into()),
+        v1::Addresses::Tcp6(a) => Some((a.source_address, a.source_port).into()),
+        v1::Addresses::Unknown => None,
+    })
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
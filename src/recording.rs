@@ -0,0 +1,109 @@
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use russh::ChannelId;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+/// Number of output chunks the recording may fall behind before chunks are left out.
+const CAPACITY: usize = 1024;
+
+/// Records the output of a terminal session to a file in the ttyrec format, replayable with
+/// `ttyplay`.
+///
+/// The file is written by a task of its own, so a slow disk never holds up the session.
+#[derive(Clone)]
+pub struct Recorder {
+    frames: mpsc::Sender<(SystemTime, Vec<u8>)>,
+    /// Whether chunks were left out, to only warn once.
+    lagged: Arc<AtomicBool>,
+    path: Arc<PathBuf>,
+}
+
+impl Recorder {
+    /// Start recording `channel` of `connection` of `user` to a new file in `dir`.
+    ///
+    /// The file is only readable by the server's user.
+    pub fn create(
+        dir: &Path,
+        user: &str,
+        connection: usize,
+        channel: ChannelId,
+    ) -> anyhow::Result<Self> {
+        let started = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace([':', '-'], "");
+        let name = format!("{}-{}-{}-{}.ttyrec", user, started, connection, channel);
+        let path = dir.join(name.replace('/', "_"));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("failed to create recording {}", path.display()))?;
+        tracing::info!("Recording the session to {}", path.display());
+        let (frames, receiver) = mpsc::channel(CAPACITY);
+        let path = Arc::new(path);
+        let writer = write(tokio::fs::File::from_std(file), receiver, path.clone());
+        tokio::spawn(writer.in_current_span());
+        Ok(Recorder {
+            frames,
+            lagged: Arc::default(),
+            path,
+        })
+    }
+
+    /// Add `output` of the session to the recording, without waiting for it to be written.
+    pub fn record(&self, output: &[u8]) {
+        let frame = (SystemTime::now(), output.to_vec());
+        if self.frames.try_send(frame).is_err() && !self.lagged.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                "Recording {} fell behind, leaving out some output",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Write the frames from `receiver` to `file` until all recorders are dropped.
+async fn write(
+    file: tokio::fs::File,
+    mut receiver: mpsc::Receiver<(SystemTime, Vec<u8>)>,
+    path: Arc<PathBuf>,
+) {
+    let mut file = BufWriter::new(file);
+    let mut result = Ok(());
+    while let Some((time, data)) = receiver.recv().await {
+        result = write_frame(&mut file, time, &data).await;
+        // Flush once caught up, so the recording can be followed while the session runs.
+        if result.is_ok() && receiver.is_empty() {
+            result = file.flush().await;
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+    if let Err(e) = result.and(file.flush().await) {
+        tracing::warn!("Failed to write recording {}: {}", path.display(), e);
+    }
+}
+
+/// Write a ttyrec frame: seconds, microseconds and length as little-endian 32-bit integers.
+async fn write_frame(
+    file: &mut BufWriter<tokio::fs::File>,
+    time: SystemTime,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut header = [0; 12];
+    header[..4].copy_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+    header[8..].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    file.write_all(&header).await?;
+    file.write_all(data).await
+}
@@ -0,0 +1,58 @@
+use std::io;
+use std::time::Duration;
+
+use russh::Disconnect;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+const MSG_DISCONNECT: u8 = 1;
+
+/// Packets without a cipher are padded to a multiple of this, see RFC 4253, section 6.
+const BLOCK_SIZE: usize = 8;
+
+const MIN_PADDING: usize = 4;
+
+/// How long a client may take to receive the message.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send the identification string `id` and then a disconnect message to `stream`.
+///
+/// This refuses a client before the key exchange, so the message is not encrypted. Clients
+/// show the `description` instead of a closed connection.
+pub async fn disconnect<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    id: &str,
+    reason: Disconnect,
+    description: &str,
+) -> io::Result<()> {
+    let mut message = format!("{}\r\n", id).into_bytes();
+    message.extend_from_slice(&packet(reason, description));
+    tokio::time::timeout(TIMEOUT, async {
+        stream.write_all(&message).await?;
+        stream.shutdown().await
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))?
+}
+
+/// An unencrypted binary packet holding `SSH_MSG_DISCONNECT`.
+fn packet(reason: Disconnect, description: &str) -> Vec<u8> {
+    let mut payload = vec![MSG_DISCONNECT];
+    payload.extend_from_slice(&(reason as u32).to_be_bytes());
+    for field in [description, "en"] {
+        payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        payload.extend_from_slice(field.as_bytes());
+    }
+    // The length, padding length and payload, followed by the padding.
+    let unpadded = 4 + 1 + payload.len();
+    let mut padding = BLOCK_SIZE - unpadded % BLOCK_SIZE;
+    if padding < MIN_PADDING {
+        padding += BLOCK_SIZE;
+    }
+    let mut packet = ((1 + payload.len() + padding) as u32)
+        .to_be_bytes()
+        .to_vec();
+    packet.push(padding as u8);
+    packet.extend_from_slice(&payload);
+    packet.resize(packet.len() + padding, 0);
+    packet
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use russh::server::Handle;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+use crate::channel::{AbortOnDrop, Channels, Process, Traffic};
+use crate::forward::Forward;
+
+/// Ports listening for the clients of all connections, by connection id, the address as
+/// requested and the port actually used.
+pub type RemoteForwards = Arc<Mutex<HashMap<(usize, String, u32), RemoteForward>>>;
+
+/// A port the server listens on for the client after a `tcpip-forward` request, forwarding
+/// connections to the client in `forwarded-tcpip` channels.
+///
+/// The port is closed when dropped.
+pub struct RemoteForward {
+    _task: AbortOnDrop,
+}
+
+impl RemoteForward {
+    /// Listen on `address` and `port` as requested by the client, returning the port actually
+    /// used, which differs if `port` is 0.
+    ///
+    /// An empty `address` or `*` means all IPv4 addresses, `localhost` means the loopback
+    /// address. Channels are registered in `channels` under `connection`.
+    pub async fn listen(
+        address: &str,
+        port: u16,
+        connection: usize,
+        handle: Handle,
+        channels: Channels,
+        bandwidth_limit: Option<u64>,
+    ) -> std::io::Result<(Self, u16)> {
+        let bind_address = match address {
+            "" | "*" => "0.0.0.0",
+            "localhost" => "127.0.0.1",
+            address => address,
+        };
+        let listener = TcpListener::bind((bind_address, port)).await?;
+        let port = listener.local_addr()?.port();
+        let connected_address = address.to_string();
+        let accept = async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept forwarded connection: {}", e);
+                        tokio::time::sleep(crate::listener::ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let forwarded = match handle
+                    .channel_open_forwarded_tcpip(
+                        connected_address.clone(),
+                        port.into(),
+                        peer.ip().to_string(),
+                        peer.port().into(),
+                    )
+                    .await
+                {
+                    Ok(forwarded) => forwarded,
+                    Err(e) => {
+                        tracing::warn!("Client refused forwarded connection: {:?}", e);
+                        continue;
+                    }
+                };
+                let channel = forwarded.id();
+                tracing::info!(
+                    "Forwarding connection from {} to port {} on {:?}",
+                    peer,
+                    port,
+                    channel
+                );
+                let traffic = Arc::new(Traffic::new(bandwidth_limit));
+                // Holding the lock until the state is complete keeps data from the client
+                // waiting instead of being handled as session data.
+                let mut channels = channels.lock().await;
+                let state = channels.entry((connection, channel)).or_default();
+                state.traffic = traffic.clone();
+                state.process = Some(Process::Forward(Forward::attach(
+                    stream,
+                    channel,
+                    handle.clone(),
+                    traffic,
+                )));
+            }
+        };
+        let forward = RemoteForward {
+            _task: AbortOnDrop(tokio::spawn(accept.in_current_span())),
+        };
+        Ok((forward, port))
+    }
+}
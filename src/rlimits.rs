@@ -0,0 +1,89 @@
+use nix::sys::resource::{self, Resource};
+use serde::{Deserialize, Serialize};
+
+/// Resource limits for the processes of a user, as set with `setrlimit`.
+///
+/// Each limit is both the soft and the hard limit, so processes cannot raise it again. Limits
+/// above the hard limit of the server are lowered to it.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// CPU time of each process, in seconds.
+    pub cpu_secs: Option<u64>,
+
+    /// Virtual memory of each process, in bytes.
+    pub address_space: Option<u64>,
+
+    /// Number of processes of the user's uid, including ones outside of this server.
+    pub processes: Option<u64>,
+
+    /// Number of open files of each process.
+    pub open_files: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.limits().all(|(_, _, limit)| limit.is_none())
+    }
+
+    /// Mistakes in the limits, named after their settings.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, resource, limit) in self.limits() {
+            match (resource, limit) {
+                (_, Some(0)) => problems.push(format!("{} must be positive", name)),
+                (None, Some(_)) => {
+                    problems.push(format!("{} is not supported on this platform", name))
+                }
+                _ => {}
+            }
+        }
+        problems
+    }
+
+    /// Apply the limits to the current process.
+    ///
+    /// This runs in the child process before `exec`, so it must not allocate.
+    pub fn apply(&self) -> nix::Result<()> {
+        for (_, resource, limit) in self.limits() {
+            let (Some(resource), Some(limit)) = (resource, limit) else {
+                continue;
+            };
+            let (_, hard) = resource::getrlimit(resource)?;
+            let limit = (limit as resource::rlim_t).min(hard);
+            resource::setrlimit(resource, limit, limit)?;
+        }
+        Ok(())
+    }
+
+    /// The configured limits with their names and resources, if supported.
+    fn limits(&self) -> impl Iterator<Item = (&'static str, Option<Resource>, Option<u64>)> {
+        #[cfg(not(any(target_os = "freebsd", target_os = "netbsd", target_os = "openbsd")))]
+        let address_space = Some(Resource::RLIMIT_AS);
+        #[cfg(any(target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+        let address_space = None;
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+        ))]
+        let processes = Some(Resource::RLIMIT_NPROC);
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+        )))]
+        let processes = None;
+        [
+            ("cpu_secs", Some(Resource::RLIMIT_CPU), self.cpu_secs),
+            ("address_space", address_space, self.address_space),
+            ("processes", processes, self.processes),
+            ("open_files", Some(Resource::RLIMIT_NOFILE), self.open_files),
+        ]
+        .into_iter()
+    }
+}
@@ -0,0 +1,522 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use anyhow::Context;
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle as FileHandle, Name, OpenFlags, Status, StatusCode,
+    Version,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::process::ChildStdin;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+use crate::channel::{AbortOnDrop, Login};
+
+/// Size of the in-memory pipe between stdio and the SFTP server in the child process.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Upper bound for a single read, regardless of what the client asks for.
+const MAX_READ_LEN: u32 = 256 * 1024;
+
+/// What the server tells the SFTP child process, as JSON on its command line.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChildSettings {
+    /// Directory to serve, as seen from within the chroot of `login`, if any.
+    pub root: PathBuf,
+    pub login: Login,
+    /// Id of the connection, for log messages.
+    pub connection: usize,
+    pub log_format: crate::LogFormat,
+    pub log_level: String,
+}
+
+/// An SFTP server attached to a channel.
+///
+/// The server runs in a child process that switches to the user's ids and chroot, so it can
+/// access no more than the user's own shell could. It is killed once the last clone is dropped.
+#[derive(Clone)]
+pub struct Sftp {
+    input: Arc<Mutex<Option<ChildStdin>>>,
+    _task: Arc<AbortOnDrop>,
+}
+
+impl Sftp {
+    /// Serve the files below `settings.root` on `channel`.
+    ///
+    /// Clients see the root as `/` and cannot access anything outside of it.
+    pub fn spawn(
+        settings: &ChildSettings,
+        channel: ChannelId,
+        handle: Handle,
+        traffic: Arc<crate::channel::Traffic>,
+    ) -> anyhow::Result<Self> {
+        let exe = std::env::current_exe().context("failed to find the server executable")?;
+        // The child starts with the privileges of the server and drops them itself, as it
+        // could not be executed from within the chroot. Its log goes to the server's stderr.
+        let mut child = tokio::process::Command::new(&exe)
+            .arg("sftp-server")
+            .arg(serde_json::to_string(settings)?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to run {}", exe.display()))?;
+        let input = child.stdin.take();
+        let mut output = child
+            .stdout
+            .take()
+            .context("no stdout of the SFTP server")?;
+        let forward = async move {
+            let mut buffer = [0; 4096];
+            loop {
+                match output.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        traffic.pace_sent(n).await;
+                        if handle
+                            .data(channel, CryptoVec::from_slice(&buffer[..n]))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+            if let Ok(status) = child.wait().await {
+                if !status.success() {
+                    tracing::warn!("SFTP server exited with {}", status);
+                }
+            }
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        };
+        Ok(Sftp {
+            input: Arc::new(Mutex::new(input)),
+            _task: Arc::new(AbortOnDrop(tokio::spawn(forward.in_current_span()))),
+        })
+    }
+
+    /// Send SFTP packets from the client to the server.
+    pub async fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        match self.input.lock().await.as_mut() {
+            Some(input) => input.write_all(data).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Tell the server that the client will not send anything else.
+    pub async fn close_input(&self) -> std::io::Result<()> {
+        self.input.lock().await.take();
+        Ok(())
+    }
+}
+
+/// Serve SFTP on stdin and stdout as the child process started by [`Sftp::spawn`].
+pub async fn serve_child(settings: &str) -> anyhow::Result<()> {
+    let settings: ChildSettings =
+        serde_json::from_str(settings).context("invalid SFTP server settings")?;
+    crate::init_logging(settings.log_format, &settings.log_level)?;
+    let span = tracing::info_span!("sftp", connection = settings.connection);
+    settings
+        .login
+        .enter()
+        .context("failed to switch to the user")?;
+    let root = settings
+        .root
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", settings.root.display()))?;
+    let (ours, theirs) = tokio::io::duplex(PIPE_SIZE);
+    russh_sftp::server::run(theirs, Session::new(root, span)).await;
+    let (mut output, mut input) = tokio::io::split(ours);
+    let receive = async {
+        let _ = tokio::io::copy(&mut tokio::io::stdin(), &mut input).await;
+        // Ends the SFTP server, which then closes its end of the pipe.
+        let _ = input.shutdown().await;
+    };
+    let mut stdout = tokio::io::stdout();
+    let send = async {
+        tokio::io::copy(&mut output, &mut stdout).await?;
+        stdout.flush().await
+    };
+    let (_, sent) = tokio::join!(receive, send);
+    sent?;
+    Ok(())
+}
+
+enum OpenHandle {
+    File(tokio::fs::File),
+    /// Directory entries not yet returned by `readdir`.
+    Dir(Option<Vec<File>>),
+}
+
+/// SFTP request handler of a single session, jailed to `root`.
+struct Session {
+    root: PathBuf,
+    /// Span of the connection, since the SFTP server runs in a task of its own.
+    span: tracing::Span,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+impl Session {
+    fn new(root: PathBuf, span: tracing::Span) -> Self {
+        Session {
+            root,
+            span,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// Normalize a client path into an absolute path within the jail, without touching the disk.
+    fn virtual_path(path: &str) -> PathBuf {
+        let mut normalized = PathBuf::from("/");
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => normalized.push(part),
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        normalized
+    }
+
+    /// Map a client path to a path on disk, rejecting anything that escapes `root`.
+    ///
+    /// Symbolic links are resolved, so a link pointing outside of `root` is rejected as well. The
+    /// last component may not exist yet, e.g. for files about to be created.
+    fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        match self.real_path(path).canonicalize() {
+            Ok(canonical) => self.check_jail(path, canonical),
+            Err(_) => self.resolve_parent(path),
+        }
+    }
+
+    /// Like [`Session::resolve`], but without following a symbolic link in the last component.
+    fn resolve_parent(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let real = self.real_path(path);
+        let canonical = match (real.parent(), real.file_name()) {
+            (Some(parent), Some(name)) => parent.canonicalize().map_err(io_status)?.join(name),
+            _ => self.root.clone(),
+        };
+        self.check_jail(path, canonical)
+    }
+
+    fn real_path(&self, path: &str) -> PathBuf {
+        let virtual_path = Self::virtual_path(path);
+        self.root
+            .join(virtual_path.strip_prefix("/").unwrap_or(&virtual_path))
+    }
+
+    fn check_jail(&self, path: &str, canonical: PathBuf) -> Result<PathBuf, StatusCode> {
+        if canonical.starts_with(&self.root) {
+            Ok(canonical)
+        } else {
+            tracing::warn!(parent: &self.span, "Rejecting SFTP access to {} outside of the root", path);
+            Err(StatusCode::PermissionDenied)
+        }
+    }
+
+    fn insert_handle(&mut self, handle: OpenHandle) -> String {
+        let name = self.next_handle.to_string();
+        self.next_handle += 1;
+        self.handles.insert(name.clone(), handle);
+        name
+    }
+
+    fn file(&mut self, handle: &str) -> Result<&mut tokio::fs::File, StatusCode> {
+        match self.handles.get_mut(handle) {
+            Some(OpenHandle::File(file)) => Ok(file),
+            _ => Err(StatusCode::Failure),
+        }
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: StatusCode::Ok.to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn io_status(e: std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        std::io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+impl russh_sftp::server::Handler for Session {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(
+        &mut self,
+        _version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<FileHandle, Self::Error> {
+        let path = self.resolve(&filename)?;
+        let mut options = std::fs::OpenOptions::from(pflags);
+        // `resolve` leaves a dangling link in place, and the last component may have been
+        // replaced by a link since, so refuse to follow one instead of creating its target.
+        options.custom_flags(nix::libc::O_NOFOLLOW);
+        let file = match tokio::fs::OpenOptions::from(options).open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.raw_os_error() == Some(nix::libc::ELOOP) => {
+                tracing::warn!(
+                    parent: &self.span,
+                    "Rejecting SFTP access to {} through a symbolic link",
+                    filename
+                );
+                return Err(StatusCode::PermissionDenied);
+            }
+            Err(e) => return Err(io_status(e)),
+        };
+        let handle = self.insert_handle(OpenHandle::File(file));
+        Ok(FileHandle { id, handle })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        match self.handles.remove(&handle) {
+            Some(_) => Ok(ok(id)),
+            None => Err(StatusCode::Failure),
+        }
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(io_status)?;
+        let mut data = vec![0; len.min(MAX_READ_LEN) as usize];
+        let n = file.read(&mut data).await.map_err(io_status)?;
+        if n == 0 && len > 0 {
+            return Err(StatusCode::Eof);
+        }
+        data.truncate(n);
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(io_status)?;
+        file.write_all(&data).await.map_err(io_status)?;
+        Ok(ok(id))
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let path = self.resolve_parent(&path)?;
+        let metadata = tokio::fs::symlink_metadata(path).await.map_err(io_status)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let metadata = self.file(&handle)?.metadata().await.map_err(io_status)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let path = self.resolve(&path)?;
+        if let Some(permissions) = attrs.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(permissions & 0o7777);
+            tokio::fs::set_permissions(path, permissions)
+                .await
+                .map_err(io_status)?;
+        }
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let file = self.file(&handle)?;
+        if let Some(size) = attrs.size {
+            file.set_len(size).await.map_err(io_status)?;
+        }
+        if let Some(permissions) = attrs.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(permissions & 0o7777);
+            file.set_permissions(permissions).await.map_err(io_status)?;
+        }
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<FileHandle, Self::Error> {
+        let path = self.resolve(&path)?;
+        let mut entries = tokio::fs::read_dir(path).await.map_err(io_status)?;
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(io_status)? {
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            files.push(File::new(
+                entry.file_name().to_string_lossy(),
+                FileAttributes::from(&metadata),
+            ));
+        }
+        let handle = self.insert_handle(OpenHandle::Dir(Some(files)));
+        Ok(FileHandle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Dir(entries)) => match entries.take() {
+                Some(files) => Ok(Name { id, files }),
+                None => Err(StatusCode::Eof),
+            },
+            _ => Err(StatusCode::Failure),
+        }
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        let path = self.resolve_parent(&filename)?;
+        tokio::fs::remove_file(path).await.map_err(io_status)?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let path = self.resolve(&path)?;
+        tokio::fs::create_dir(path).await.map_err(io_status)?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        let path = self.resolve(&path)?;
+        tokio::fs::remove_dir(path).await.map_err(io_status)?;
+        Ok(ok(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = Self::virtual_path(&path);
+        Ok(Name {
+            id,
+            files: vec![File::dummy(path.to_string_lossy())],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let path = self.resolve(&path)?;
+        let metadata = tokio::fs::metadata(path).await.map_err(io_status)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        let oldpath = self.resolve_parent(&oldpath)?;
+        let newpath = self.resolve_parent(&newpath)?;
+        tokio::fs::rename(oldpath, newpath)
+            .await
+            .map_err(io_status)?;
+        Ok(ok(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use russh_sftp::server::Handler;
+
+    use super::*;
+
+    fn session(root: &Path) -> Session {
+        Session::new(root.canonicalize().unwrap(), tracing::Span::none())
+    }
+
+    #[tokio::test]
+    async fn refuses_dangling_link_out_of_root() {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let target = outside.path().join("evil");
+        std::os::unix::fs::symlink(&target, root.path().join("x")).unwrap();
+
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let opened = session(root.path())
+            .open(1, "/x".to_string(), flags, FileAttributes::default())
+            .await;
+
+        assert_eq!(opened.err(), Some(StatusCode::PermissionDenied));
+        assert!(!target.exists());
+    }
+
+    #[tokio::test]
+    async fn creates_files_in_root() {
+        let root = tempfile::tempdir().unwrap();
+
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        let opened = session(root.path())
+            .open(1, "/new".to_string(), flags, FileAttributes::default())
+            .await;
+
+        assert!(opened.is_ok());
+        assert!(root.path().join("new").is_file());
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use pty_process::{OwnedWritePty, Size};
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec, Pty};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+use crate::channel;
+use crate::recording::Recorder;
+
+/// Shell used when `$SHELL` is not set.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// The login shell for users without a configured one: `$SHELL` of the server, or `/bin/sh`.
+pub fn default_shell() -> String {
+    std::env::var("SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_string())
+}
+
+/// Terminal size used when the client did not request a pseudo-terminal.
+const DEFAULT_SIZE: (u16, u16) = (24, 80);
+
+/// A pseudo-terminal requested by the client with `pty-req`.
+#[derive(Debug, Clone)]
+pub struct PtyRequest {
+    pub term: String,
+    pub size: Size,
+    /// Terminal modes, e.g. control characters and whether to echo input.
+    pub modes: Vec<(Pty, u32)>,
+}
+
+impl PtyRequest {
+    pub fn new(
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+        modes: &[(Pty, u32)],
+    ) -> Self {
+        PtyRequest {
+            term: term.to_string(),
+            size: size(col_width, row_height, pix_width, pix_height),
+            // russh pads the modes with the end marker.
+            modes: modes
+                .iter()
+                .filter(|(mode, _)| *mode != Pty::TTY_OP_END)
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+/// Convert the dimensions of a `pty-req` or `window-change` request into a PTY size.
+pub fn size(col_width: u32, row_height: u32, pix_width: u32, pix_height: u32) -> Size {
+    let clamp = |value: u32| u16::try_from(value).unwrap_or(u16::MAX);
+    Size::new_with_pixel(
+        clamp(row_height),
+        clamp(col_width),
+        clamp(pix_width),
+        clamp(pix_height),
+    )
+}
+
+/// A process attached to a channel through a pseudo-terminal.
+///
+/// Once the last clone is dropped, the terminal is closed, which hangs up the process.
+#[derive(Clone)]
+pub struct Shell {
+    pty: Arc<Mutex<OwnedWritePty>>,
+    group: channel::ProcessGroup,
+    _output: Arc<channel::AbortOnDrop>,
+}
+
+impl Shell {
+    /// Spawn `program` as login shell on a new pseudo-terminal, for `login` and with the
+    /// additional environment `env`.
+    ///
+    /// Output of the shell is sent to `channel` until it exits, after which the exit status is
+    /// reported and the channel is closed. It is also added to `recording`, if any.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn_login(
+        program: &str,
+        login: &channel::Login,
+        pty: Option<&PtyRequest>,
+        env: &[(String, String)],
+        channel: ChannelId,
+        handle: Handle,
+        traffic: Arc<channel::Traffic>,
+        recording: Option<Recorder>,
+    ) -> anyhow::Result<Self> {
+        let mut command = pty_process::Command::new(program);
+        // A leading dash tells the shell to behave as a login shell.
+        command.arg0(format!(
+            "-{}",
+            program.rsplit('/').next().unwrap_or(program)
+        ));
+        command.envs(env.iter().cloned());
+        login.configure_pty(&mut command);
+        Self::spawn(command, pty, channel, handle, traffic, recording)
+            .with_context(|| format!("failed to spawn shell {}", program))
+    }
+
+    /// Spawn `command` on a new pseudo-terminal, like [`Shell::spawn_login`].
+    pub fn spawn(
+        mut command: pty_process::Command,
+        pty: Option<&PtyRequest>,
+        channel: ChannelId,
+        handle: Handle,
+        traffic: Arc<channel::Traffic>,
+        recording: Option<Recorder>,
+    ) -> anyhow::Result<Self> {
+        let master = pty_process::Pty::new().context("failed to allocate pseudo-terminal")?;
+        let size = pty
+            .map(|pty| pty.size)
+            .unwrap_or_else(|| Size::new(DEFAULT_SIZE.0, DEFAULT_SIZE.1));
+        master.resize(size).context("failed to set terminal size")?;
+
+        if let Some(pty) = pty {
+            command.env("TERM", &pty.term);
+            // Like a terminal that ignores settings it does not support, leave it as it is.
+            if let Err(e) = crate::terminal::apply(&master, &pty.modes) {
+                tracing::warn!("Failed to apply terminal modes: {}", e);
+            }
+        }
+        let pts = master.pts().context("failed to open pseudo-terminal")?;
+        let mut child = command.spawn(&pts)?;
+        drop(pts);
+        // The process is the leader of a new session, and thus of a process group.
+        let group = channel::ProcessGroup::new(child.id());
+
+        let (mut reader, writer) = master.into_split();
+        let reaped = group.clone();
+        let forward = async move {
+            let mut buffer = [0; 4096];
+            loop {
+                match reader.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        traffic.pace_sent(n).await;
+                        if let Some(recording) = &recording {
+                            recording.record(&buffer[..n]);
+                        }
+                        if handle
+                            .data(channel, CryptoVec::from_slice(&buffer[..n]))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+            let status = child.wait().await;
+            reaped.reaped();
+            channel::finish(&handle, channel, status).await;
+        };
+        let output = tokio::spawn(forward.in_current_span());
+
+        Ok(Shell {
+            pty: Arc::new(Mutex::new(writer)),
+            group,
+            _output: Arc::new(channel::AbortOnDrop(output)),
+        })
+    }
+
+    /// Send input to the process.
+    pub async fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        self.pty.lock().await.write_all(data).await
+    }
+
+    /// Send `signal` to the process.
+    pub fn signal(&self, signal: nix::sys::signal::Signal) -> nix::Result<()> {
+        self.group.signal(signal)
+    }
+
+    /// Update the terminal size, e.g. after a `window-change` request.
+    pub async fn resize(&self, size: Size) -> anyhow::Result<()> {
+        self.pty
+            .lock()
+            .await
+            .resize(size)
+            .context("failed to set terminal size")
+    }
+}
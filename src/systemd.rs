@@ -0,0 +1,41 @@
+use sd_notify::NotifyState;
+
+/// Tell systemd that the server is listening, or done reloading.
+pub fn ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+/// Tell systemd that the configuration is being reloaded.
+pub fn reloading() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(e) => tracing::warn!("Failed to read the monotonic clock: {}", e),
+    }
+}
+
+/// Tell systemd that the server is shutting down.
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Keep the watchdog from restarting the server, if systemd enabled it for the service.
+pub fn start_watchdog() {
+    let Some(timeout) = sd_notify::watchdog_enabled() else {
+        return;
+    };
+    tracing::debug!("Pinging the systemd watchdog every {:?}", timeout / 2);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(timeout / 2);
+        loop {
+            interval.tick().await;
+            notify(&[NotifyState::Watchdog]);
+        }
+    });
+}
+
+/// Send `state` to systemd, doing nothing unless it started the server with `Type=notify`.
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(state) {
+        tracing::warn!("Failed to notify systemd: {}", e);
+    }
+}
@@ -0,0 +1,149 @@
+use std::os::fd::AsFd;
+
+use nix::sys::termios::{
+    self, BaudRate, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg,
+    SpecialCharacterIndices,
+};
+use russh::Pty;
+
+/// Value of a control character the client disabled, see RFC 4254, section 8.
+const DISABLED: u32 = 255;
+
+/// Apply the terminal `modes` of a `pty-req` to the terminal `fd`.
+///
+/// Modes this platform does not know are ignored, like OpenSSH does.
+pub fn apply(fd: impl AsFd, modes: &[(Pty, u32)]) -> nix::Result<()> {
+    let mut settings = termios::tcgetattr(&fd)?;
+    for &(mode, value) in modes {
+        match mode {
+            Pty::TTY_OP_ISPEED => {
+                if let Some(rate) = baud_rate(value) {
+                    termios::cfsetispeed(&mut settings, rate)?;
+                }
+            }
+            Pty::TTY_OP_OSPEED => {
+                if let Some(rate) = baud_rate(value) {
+                    termios::cfsetospeed(&mut settings, rate)?;
+                }
+            }
+            _ => apply_mode(&mut settings, mode, value),
+        }
+    }
+    termios::tcsetattr(&fd, SetArg::TCSANOW, &settings)
+}
+
+/// Apply a control character or flag.
+fn apply_mode(settings: &mut termios::Termios, mode: Pty, value: u32) {
+    if let Some(index) = control_character(mode) {
+        // Disabled characters are 0 on Linux and the BSDs.
+        let value = if value == DISABLED { 0 } else { value };
+        settings.control_chars[index as usize] = u8::try_from(value).unwrap_or_default();
+    } else if let Some(flag) = input_flag(mode) {
+        settings.input_flags.set(flag, value != 0);
+    } else if let Some(flag) = local_flag(mode) {
+        settings.local_flags.set(flag, value != 0);
+    } else if let Some(flag) = output_flag(mode) {
+        settings.output_flags.set(flag, value != 0);
+    } else if let Some(flag) = control_flag(mode) {
+        // The character sizes share a field.
+        if value != 0 && matches!(mode, Pty::CS7 | Pty::CS8) {
+            settings.control_flags.remove(ControlFlags::CSIZE);
+        }
+        settings.control_flags.set(flag, value != 0);
+    }
+}
+
+fn control_character(mode: Pty) -> Option<SpecialCharacterIndices> {
+    use SpecialCharacterIndices as C;
+    Some(match mode {
+        Pty::VINTR => C::VINTR,
+        Pty::VQUIT => C::VQUIT,
+        Pty::VERASE => C::VERASE,
+        Pty::VKILL => C::VKILL,
+        Pty::VEOF => C::VEOF,
+        Pty::VEOL => C::VEOL,
+        Pty::VEOL2 => C::VEOL2,
+        Pty::VSTART => C::VSTART,
+        Pty::VSTOP => C::VSTOP,
+        Pty::VSUSP => C::VSUSP,
+        Pty::VREPRINT => C::VREPRINT,
+        Pty::VWERASE => C::VWERASE,
+        Pty::VLNEXT => C::VLNEXT,
+        Pty::VDISCARD => C::VDISCARD,
+        _ => return None,
+    })
+}
+
+fn input_flag(mode: Pty) -> Option<InputFlags> {
+    Some(match mode {
+        Pty::IGNPAR => InputFlags::IGNPAR,
+        Pty::PARMRK => InputFlags::PARMRK,
+        Pty::INPCK => InputFlags::INPCK,
+        Pty::ISTRIP => InputFlags::ISTRIP,
+        Pty::INLCR => InputFlags::INLCR,
+        Pty::IGNCR => InputFlags::IGNCR,
+        Pty::ICRNL => InputFlags::ICRNL,
+        Pty::IXON => InputFlags::IXON,
+        Pty::IXANY => InputFlags::IXANY,
+        Pty::IXOFF => InputFlags::IXOFF,
+        Pty::IMAXBEL => InputFlags::IMAXBEL,
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        Pty::IUTF8 => InputFlags::IUTF8,
+        _ => return None,
+    })
+}
+
+fn local_flag(mode: Pty) -> Option<LocalFlags> {
+    Some(match mode {
+        Pty::ISIG => LocalFlags::ISIG,
+        Pty::ICANON => LocalFlags::ICANON,
+        Pty::ECHO => LocalFlags::ECHO,
+        Pty::ECHOE => LocalFlags::ECHOE,
+        Pty::ECHOK => LocalFlags::ECHOK,
+        Pty::ECHONL => LocalFlags::ECHONL,
+        Pty::NOFLSH => LocalFlags::NOFLSH,
+        Pty::TOSTOP => LocalFlags::TOSTOP,
+        Pty::IEXTEN => LocalFlags::IEXTEN,
+        Pty::ECHOCTL => LocalFlags::ECHOCTL,
+        Pty::ECHOKE => LocalFlags::ECHOKE,
+        Pty::PENDIN => LocalFlags::PENDIN,
+        _ => return None,
+    })
+}
+
+fn output_flag(mode: Pty) -> Option<OutputFlags> {
+    Some(match mode {
+        Pty::OPOST => OutputFlags::OPOST,
+        Pty::ONLCR => OutputFlags::ONLCR,
+        Pty::OCRNL => OutputFlags::OCRNL,
+        Pty::ONOCR => OutputFlags::ONOCR,
+        Pty::ONLRET => OutputFlags::ONLRET,
+        _ => return None,
+    })
+}
+
+fn control_flag(mode: Pty) -> Option<ControlFlags> {
+    Some(match mode {
+        Pty::CS7 => ControlFlags::CS7,
+        Pty::CS8 => ControlFlags::CS8,
+        Pty::PARENB => ControlFlags::PARENB,
+        Pty::PARODD => ControlFlags::PARODD,
+        _ => return None,
+    })
+}
+
+/// The rate of `speed` bits per second, if it is a standard one.
+fn baud_rate(speed: u32) -> Option<BaudRate> {
+    Some(match speed {
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        _ => return None,
+    })
+}
@@ -0,0 +1,47 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Paces data to a number of bytes per second, with bursts of up to a second's worth.
+pub struct Throttle {
+    /// Bytes per second, which is also the size of the bucket.
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may be sent right away, negative once the budget is overdrawn.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        Throttle {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `len` bytes from the budget, waiting until it is no longer overdrawn.
+    ///
+    /// Chunks larger than the bucket overdraw it instead of waiting for tokens that never fit,
+    /// so the wait only depends on `len` and never on the client, e.g. its channel window.
+    pub async fn take(&self, len: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.rate) - len as f64;
+            bucket.updated = now;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use totp_lite::Sha1;
+
+/// Seconds each code is valid for, as used by authenticator apps.
+const STEP: u64 = 30;
+
+const DIGITS: u32 = 6;
+
+/// Number of steps a code may be early or late, allowing for clock skew and slow typing.
+const SKEW: u64 = 1;
+
+/// The shared secret of a user's authenticator app, given in base32 like in `otpauth://` URIs.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    /// Check `code` against the codes of the current time and [`SKEW`] steps around it.
+    pub fn verify(&self, code: &str) -> bool {
+        let code = code.trim();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let mut valid = false;
+        for step in 0..=2 * SKEW {
+            let time = (now + step * STEP).saturating_sub(SKEW * STEP);
+            let expected = totp_lite::totp_custom::<Sha1>(STEP, DIGITS, &self.0, time);
+            valid |= bool::from(expected.as_bytes().ct_eq(code.as_bytes()));
+        }
+        valid
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        // Apps show secrets in groups and some tools pad them, neither of which matters.
+        let normalized: String = value
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        match data_encoding::BASE32_NOPAD.decode(normalized.as_bytes()) {
+            Ok(secret) if !secret.is_empty() => Ok(Secret(secret)),
+            _ => Err("totp_secret is not a base32-encoded secret".to_string()),
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use russh::server::Handle;
+use tokio::net::TcpListener;
+use tracing::Instrument;
+
+use crate::channel::{AbortOnDrop, Channels, Process, Traffic};
+use crate::forward::Forward;
+
+/// First display number to try, leaving the lower ones to local X servers.
+const DISPLAY_OFFSET: u16 = 10;
+
+/// Number of displays to try before giving up.
+const MAX_DISPLAYS: u16 = 1000;
+
+/// X11 displays listen on TCP port 6000 plus their number.
+const BASE_PORT: u16 = 6000;
+
+/// Xauthority family matching any host.
+const FAMILY_WILD: u16 = 0xffff;
+
+/// What the client asked for in an `x11-req`.
+pub struct Request<'a> {
+    pub single_connection: bool,
+    pub auth_protocol: &'a str,
+    /// Cookie in hexadecimal.
+    pub auth_cookie: &'a str,
+    pub screen_number: u32,
+}
+
+/// A local X11 display whose connections are forwarded to the client in `x11` channels.
+///
+/// The display stops accepting connections and its authority file is removed when dropped.
+pub struct Display {
+    number: u16,
+    screen: u32,
+    authority: PathBuf,
+    _task: AbortOnDrop,
+}
+
+impl Display {
+    /// Listen on the first free display, accepting the cookie of `request`.
+    ///
+    /// The authority file is owned by `ids` if given, so that processes running as that user
+    /// can read it. Channels are registered in `channels` under `connection`.
+    pub async fn open(
+        request: &Request<'_>,
+        ids: Option<(u32, u32)>,
+        connection: usize,
+        handle: Handle,
+        channels: Channels,
+        bandwidth_limit: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let cookie = data_encoding::HEXLOWER_PERMISSIVE
+            .decode(request.auth_cookie.as_bytes())
+            .context("invalid X11 cookie")?;
+        let (number, listener) = bind().await?;
+        let authority = std::env::temp_dir().join(format!("russh-server-x11-{}.auth", number));
+        write_authority(&authority, number, request.auth_protocol, &cookie, ids)
+            .with_context(|| format!("failed to write {}", authority.display()))?;
+
+        let single_connection = request.single_connection;
+        let accept = async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept X11 connection: {}", e);
+                        tokio::time::sleep(crate::listener::ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let x11 = match handle
+                    .channel_open_x11(peer.ip().to_string(), peer.port().into())
+                    .await
+                {
+                    Ok(x11) => x11,
+                    Err(e) => {
+                        tracing::warn!("Client refused X11 channel: {:?}", e);
+                        continue;
+                    }
+                };
+                let channel = x11.id();
+                tracing::info!("Forwarding X11 connection from {} on {:?}", peer, channel);
+                let traffic = Arc::new(Traffic::new(bandwidth_limit));
+                // Holding the lock until the state is complete keeps data from the client
+                // waiting instead of being handled as session data.
+                let mut channels = channels.lock().await;
+                let state = channels.entry((connection, channel)).or_default();
+                state.traffic = traffic.clone();
+                state.process = Some(Process::Forward(Forward::attach(
+                    stream,
+                    channel,
+                    handle.clone(),
+                    traffic,
+                )));
+                if single_connection {
+                    break;
+                }
+            }
+        };
+        Ok(Display {
+            number,
+            screen: request.screen_number,
+            authority,
+            _task: AbortOnDrop(tokio::spawn(accept.in_current_span())),
+        })
+    }
+
+    /// Variables telling X11 clients where to find the display.
+    pub fn env(&self) -> Vec<(String, String)> {
+        vec![
+            ("DISPLAY".to_string(), format!("{}.{}", self, self.screen)),
+            (
+                "XAUTHORITY".to_string(),
+                self.authority.display().to_string(),
+            ),
+        ]
+    }
+}
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "localhost:{}", self.number)
+    }
+}
+
+impl Drop for Display {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.authority) {
+            tracing::warn!("Failed to remove {}: {}", self.authority.display(), e);
+        }
+    }
+}
+
+/// Listen on the first display number that is not in use.
+async fn bind() -> anyhow::Result<(u16, TcpListener)> {
+    for number in DISPLAY_OFFSET..DISPLAY_OFFSET + MAX_DISPLAYS {
+        if let Ok(listener) = TcpListener::bind(("127.0.0.1", BASE_PORT + number)).await {
+            return Ok((number, listener));
+        }
+    }
+    anyhow::bail!("no free X11 display")
+}
+
+/// Write an Xauthority file with a single entry for display `number`.
+fn write_authority(
+    path: &Path,
+    number: u16,
+    protocol: &str,
+    cookie: &[u8],
+    ids: Option<(u32, u32)>,
+) -> std::io::Result<()> {
+    // A file left behind by a crashed server would block the display forever.
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    if let Some((uid, gid)) = ids {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+    }
+    let mut entry = FAMILY_WILD.to_be_bytes().to_vec();
+    for field in [
+        &b""[..],
+        number.to_string().as_bytes(),
+        protocol.as_bytes(),
+        cookie,
+    ] {
+        let length = u16::try_from(field.len())
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "too long"))?;
+        entry.extend_from_slice(&length.to_be_bytes());
+        entry.extend_from_slice(field);
+    }
+    file.write_all(&entry)
+}
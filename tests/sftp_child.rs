@@ -0,0 +1,53 @@
+//! The SFTP server started for every SFTP session, run as its own process.
+
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+
+/// Ids of `nobody`, which the child has to switch to.
+const NOBODY: u32 = 65534;
+
+#[test]
+#[ignore = "needs root"]
+fn child_runs_as_the_user() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::set_permissions(root.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    let settings = serde_json::json!({
+        "root": root.path(),
+        "login": {"ids": [NOBODY, NOBODY], "rlimits": {}},
+        "connection": 1,
+        "log_format": "text",
+        "log_level": "warn",
+    });
+    let mut child = Command::new(env!("CARGO_BIN_EXE_russh-server"))
+        .arg("sftp-server")
+        .arg(settings.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // SSH_FXP_INIT of version 3, answered with SSH_FXP_VERSION once the child is serving.
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&[0, 0, 0, 5, 1, 0, 0, 0, 3]).unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut header = [0; 5];
+    stdout.read_exact(&mut header).unwrap();
+    assert_eq!(header[4], 2, "expected SSH_FXP_VERSION");
+
+    let status = std::fs::read_to_string(format!("/proc/{}/status", child.id())).unwrap();
+    let ids = |field: &str| -> Vec<u32> {
+        let line = status.lines().find(|l| l.starts_with(field)).unwrap();
+        line[field.len()..]
+            .split_whitespace()
+            .map(|id| id.parse().unwrap())
+            .collect()
+    };
+    // Real, effective, saved and filesystem ids.
+    assert_eq!(ids("Uid:"), [NOBODY; 4]);
+    assert_eq!(ids("Gid:"), [NOBODY; 4]);
+    assert!(ids("Groups:").is_empty());
+
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}